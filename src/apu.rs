@@ -0,0 +1,708 @@
+// https://wiki.nesdev.com/w/index.php/APU
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+//--------------------------------------------------------------------------------
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    loop_flag: bool,
+    constant_volume: bool,
+    volume: u8,
+    divider: u8,
+    decay_level: u8
+}
+
+impl Envelope {
+    fn write_control(&mut self, value: u8) {
+        self.loop_flag = value & 0x20 != 0;
+        self.constant_volume = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    // Clocked by the frame counter every quarter frame
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.loop_flag {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay_level }
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8
+}
+
+impl LengthCounter {
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    // Clocked by the frame counter every half frame
+    fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+struct PulseChannel {
+    // Pulse 1 uses one's complement when negating the sweep change, so subtracts one more than pulse 2
+    ones_complement_negate: bool,
+
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool
+}
+
+impl PulseChannel {
+    fn new(ones_complement_negate: bool) -> PulseChannel {
+        return PulseChannel {
+            ones_complement_negate,
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Default::default(),
+            length_counter: Default::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false
+        };
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length_counter.halt = value & 0x20 != 0;
+                self.envelope.write_control(value);
+            }
+
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 7;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 7;
+                self.sweep_reload = true;
+            }
+
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | value as u16;
+            }
+
+            _ => {
+                self.timer_period = (self.timer_period & 0xFF) | ((value as u16 & 7) << 8);
+                self.length_counter.load(value >> 3);
+                self.sequence_step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn get_sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let extra = if self.ones_complement_negate { 1 } else { 0 };
+            return self.timer_period.saturating_sub(change + extra);
+        } else {
+            return self.timer_period + change;
+        }
+    }
+
+    // The channel is silenced when the period is too low, or when the sweep unit would overflow it, even if the sweep
+    // unit is disabled
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.get_sweep_target_period() > 0x7FF
+    }
+
+    // Clocked every APU cycle, which is every second CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the frame counter every half frame
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift != 0 && !self.is_muted() {
+            self.timer_period = self.get_sweep_target_period();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length_counter.is_active() || self.is_muted() || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0 {
+            return 0;
+        }
+        return self.envelope.output();
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Default)]
+struct TriangleChannel {
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+    length_counter: LengthCounter,
+
+    control_flag: bool,
+    linear_counter: u8,
+    linear_counter_reload_value: u8,
+    linear_counter_reload: bool
+}
+
+impl TriangleChannel {
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control_flag = value & 0x80 != 0;
+                self.length_counter.halt = self.control_flag;
+                self.linear_counter_reload_value = value & 0x7F;
+            }
+
+            1 => {}
+
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | value as u16;
+            }
+
+            _ => {
+                self.timer_period = (self.timer_period & 0xFF) | ((value as u16 & 7) << 8);
+                self.length_counter.load(value >> 3);
+                self.linear_counter_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+
+            // Periods below 2 give an ultrasonic tone that real hardware smooths out to a constant level but that would
+            // alias badly here, so the sequencer is held instead
+            if self.linear_counter > 0 && self.length_counter.is_active() && self.timer_period >= 2 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the frame counter every quarter frame
+    fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control_flag {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    // Silencing the channel stops the sequencer rather than zeroing the output, so it holds its last level
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_step as usize]
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+// Timer periods in CPU cycles, NTSC
+const NOISE_PERIOD_TABLE: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+struct NoiseChannel {
+    mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter
+}
+
+impl NoiseChannel {
+    fn new() -> NoiseChannel {
+        return NoiseChannel {
+            mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            envelope: Default::default(),
+            length_counter: Default::default()
+        };
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length_counter.halt = value & 0x20 != 0;
+                self.envelope.write_control(value);
+            }
+
+            1 => {}
+
+            2 => {
+                self.mode = value & 0x80 != 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(value & 0x0F) as usize];
+            }
+
+            _ => {
+                self.length_counter.load(value >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            // Mode 1 takes feedback from bit 6 instead of bit 1, giving a short 93-step sequence that sounds metallic
+            let other_bit = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> other_bit)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length_counter.is_active() || self.shift_register & 1 != 0 {
+            return 0;
+        }
+        return self.envelope.output();
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+// Timer periods in CPU cycles, NTSC
+const DMC_RATE_TABLE: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// https://wiki.nesdev.com/w/index.php/APU_DMC
+struct DmcChannel {
+    irq_enabled: bool,
+    irq_flag: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer: u16,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8
+}
+
+impl DmcChannel {
+    fn new() -> DmcChannel {
+        return DmcChannel {
+            irq_enabled: false,
+            irq_flag: false,
+            loop_flag: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0
+        };
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.loop_flag = value & 0x40 != 0;
+                self.timer_period = DMC_RATE_TABLE[(value & 0x0F) as usize];
+            }
+
+            1 => self.output_level = value & 0x7F,
+
+            2 => self.sample_address = 0xC000 + value as u16 * 64,
+
+            _ => self.sample_length = value as u16 * 16 + 1
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn dma_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            return Some(self.current_address);
+        }
+        return None;
+    }
+
+    fn complete_dma(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // The address wraps around to $8000 rather than $0000
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 { self.output_level += 2 }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.shift_register = value;
+                    self.silence = false;
+                }
+                None => self.silence = true
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+
+pub struct Apu {
+    pulse_1: PulseChannel,
+    pulse_2: PulseChannel,
+    triangle: TriangleChannel,
+    noise: NoiseChannel,
+    dmc: DmcChannel,
+
+    cycle: u64,
+
+    // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    frame_counter_cycle: u32,
+    frame_counter_five_step: bool,
+    frame_irq_inhibit: bool,
+    frame_irq_flag: bool,
+    frame_counter_reset_delay: Option<u8>,
+
+    samples: Vec<f32>
+}
+
+// About a third of a second of audio, one sample per CPU cycle
+pub const MAX_BUFFERED_SAMPLES: usize = 1 << 19;
+
+impl Apu {
+    pub fn new() -> Apu {
+        return Apu {
+            pulse_1: PulseChannel::new(true),
+            pulse_2: PulseChannel::new(false),
+            triangle: Default::default(),
+            noise: NoiseChannel::new(),
+            dmc: DmcChannel::new(),
+            cycle: 0,
+            frame_counter_cycle: 0,
+            frame_counter_five_step: false,
+            frame_irq_inhibit: false,
+            frame_irq_flag: false,
+            frame_counter_reset_delay: None,
+            samples: Vec::new()
+        };
+    }
+
+    /// Silence all channels and restart the frame counter, as happens on reset. The frame counter keeps its mode.
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+
+        let frame_counter_value = (if self.frame_counter_five_step { 0x80 } else { 0 })
+            | (if self.frame_irq_inhibit { 0x40 } else { 0 });
+        self.write_register(0x4017, frame_counter_value);
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000 ..= 0x4003 => self.pulse_1.write_register(address - 0x4000, value),
+            0x4004 ..= 0x4007 => self.pulse_2.write_register(address - 0x4004, value),
+            0x4008 ..= 0x400B => self.triangle.write_register(address - 0x4008, value),
+            0x400C ..= 0x400F => self.noise.write_register(address - 0x400C, value),
+            0x4010 ..= 0x4013 => self.dmc.write_register(address - 0x4010, value),
+
+            0x4015 => {
+                self.pulse_1.length_counter.set_enabled(value & 0x01 != 0);
+                self.pulse_2.length_counter.set_enabled(value & 0x02 != 0);
+                self.triangle.length_counter.set_enabled(value & 0x04 != 0);
+                self.noise.length_counter.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+                self.dmc.irq_flag = false;
+            }
+
+            0x4017 => {
+                self.frame_counter_five_step = value & 0x80 != 0;
+                self.frame_irq_inhibit = value & 0x40 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq_flag = false;
+                }
+
+                // The sequencer restarts 3 CPU cycles after a write on an APU cycle, or 4 after a write between them
+                self.frame_counter_reset_delay = Some(if self.cycle % 2 == 1 { 4 } else { 3 });
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Read the status register, $4015. This acknowledges the frame IRQ.
+    pub fn read_status(&mut self) -> u8 {
+        let mut result = 0;
+        if self.pulse_1.length_counter.is_active() { result |= 0x01 }
+        if self.pulse_2.length_counter.is_active() { result |= 0x02 }
+        if self.triangle.length_counter.is_active() { result |= 0x04 }
+        if self.noise.length_counter.is_active() { result |= 0x08 }
+        if self.dmc.bytes_remaining > 0 { result |= 0x10 }
+        if self.frame_irq_flag { result |= 0x40 }
+        if self.dmc.irq_flag { result |= 0x80 }
+        self.frame_irq_flag = false;
+        return result;
+    }
+
+    /// Whether the APU is asserting the CPU's IRQ line.
+    pub fn irq(&self) -> bool {
+        self.frame_irq_flag || self.dmc.irq_flag
+    }
+
+    /// The address the DMC channel needs to fetch its next sample byte from, if its sample buffer is empty. The CPU is
+    /// halted while the byte is read, and it should be passed back with `complete_dmc_dma`.
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.dma_address()
+    }
+
+    pub fn complete_dmc_dma(&mut self, value: u8) {
+        self.dmc.complete_dma(value);
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length_counter.clock();
+        self.pulse_2.length_counter.clock();
+        self.triangle.length_counter.clock();
+        self.noise.length_counter.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.frame_irq_inhibit {
+            self.frame_irq_flag = true;
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_counter_reset_delay {
+            if delay > 1 {
+                self.frame_counter_reset_delay = Some(delay - 1);
+            } else {
+                self.frame_counter_reset_delay = None;
+                self.frame_counter_cycle = 0;
+
+                // Entering 5-step mode clocks the units immediately
+                if self.frame_counter_five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        // Step timings in CPU cycles since the sequencer restarted
+        self.frame_counter_cycle += 1;
+        match (self.frame_counter_five_step, self.frame_counter_cycle) {
+            (_, 7457) | (_, 22371) => self.clock_quarter_frame(),
+
+            (_, 14913) | (true, 37281) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+
+            (false, 29828) => self.set_frame_irq(),
+
+            (false, 29829) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.set_frame_irq();
+            }
+
+            (false, 29830) => {
+                self.set_frame_irq();
+                self.frame_counter_cycle = 0;
+            }
+
+            (true, 37282) => self.frame_counter_cycle = 0,
+
+            _ => {}
+        }
+    }
+
+    /// Advance by one CPU cycle, and produce one sample.
+    pub fn step(&mut self) {
+        if self.cycle % 2 == 1 {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        self.clock_frame_counter();
+
+        self.cycle += 1;
+        let sample = self.output();
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            // Nothing is taking the samples, so drop the oldest rather than growing without limit
+            self.samples.drain(.. MAX_BUFFERED_SAMPLES / 2);
+        }
+        self.samples.push(sample);
+    }
+
+    // https://wiki.nesdev.com/w/index.php/APU_Mixer
+    fn output(&self) -> f32 {
+        let pulse_sum = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulse_sum == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse_sum + 100.0) };
+
+        let tnd_sum = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd_sum == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd_sum + 100.0) };
+
+        return pulse_out + tnd_out;
+    }
+
+    /// Remove and return the samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::emulator::{Result, CPU_CLOCK_RATE};
+
+/// Receives the APU's output as it is produced, one sample per CPU cycle in the range 0 to 1.
+pub trait AudioSink {
+    fn write_samples(&mut self, samples: &[f32]) -> Result<()>;
+}
+
+//--------------------------------------------------------------------------------
+
+/// Downsamples APU output, which has one sample per CPU cycle, by averaging all the input samples that fall within each
+/// output sample. The DC offset is then removed with a high-pass filter like the one in the console's audio output.
+/// https://wiki.nesdev.com/w/index.php/APU_Mixer
+pub struct Resampler {
+    input_rate: f64,
+    output_rate: f64,
+    position: f64,
+    sum: f32,
+    count: u32,
+
+    highpass_coefficient: f32,
+    highpass_last_input: f32,
+    highpass_last_output: f32
+}
+
+const HIGHPASS_CUTOFF: f64 = 90.0;
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Resampler {
+        let mut result = Resampler {
+            input_rate,
+            output_rate,
+            position: 0.0,
+            sum: 0.0,
+            count: 0,
+            highpass_coefficient: 0.0,
+            highpass_last_input: 0.0,
+            highpass_last_output: 0.0
+        };
+        result.set_output_rate(output_rate);
+        return result;
+    }
+
+    pub fn output_rate(&self) -> f64 {
+        self.output_rate
+    }
+
+    /// Change the output rate. Frontends can nudge this slightly each frame to keep an audio buffer at a steady level.
+    pub fn set_output_rate(&mut self, output_rate: f64) {
+        self.output_rate = output_rate;
+
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * HIGHPASS_CUTOFF);
+        let dt = 1.0 / output_rate;
+        self.highpass_coefficient = (rc / (rc + dt)) as f32;
+    }
+
+    /// Resample `input`, appending the results to `output`. Leftover input samples are carried over to the next call.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let input_per_output = self.input_rate / self.output_rate;
+
+        for &sample in input {
+            self.sum += sample;
+            self.count += 1;
+            self.position += 1.0;
+
+            if self.position >= input_per_output {
+                self.position -= input_per_output;
+
+                let average = self.sum / self.count as f32;
+                self.sum = 0.0;
+                self.count = 0;
+
+                let filtered = self.highpass_coefficient * (self.highpass_last_output + average - self.highpass_last_input);
+                self.highpass_last_input = average;
+                self.highpass_last_output = filtered;
+                output.push(filtered);
+            }
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------
+
+/// Writes audio to a 16-bit mono PCM WAV file, resampled to the given rate.
+/// http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    resampler: Resampler,
+    resampled: Vec<f32>,
+    data_size: u32
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> Result<WavWriter<W>> {
+        // The chunk sizes are filled in by `finish`
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&1u16.to_le_bytes())?; // mono
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+        writer.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        writer.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        return Ok(WavWriter {
+            writer,
+            resampler: Resampler::new(CPU_CLOCK_RATE, sample_rate as f64),
+            resampled: Vec::new(),
+            data_size: 0
+        });
+    }
+
+    /// Fill in the chunk sizes in the header, and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(36 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        return Ok(self.writer);
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        self.resampled.clear();
+        self.resampler.process(samples, &mut self.resampled);
+
+        for sample in &self.resampled {
+            let value = (sample.clamp(-1.0, 1.0) * 32767.0) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += self.resampled.len() as u32 * 2;
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,50 @@
+#![allow(clippy::needless_return)]
+
+// Run a ROM without a window and write its audio to a WAV file, for comparing against known-good captures.
+//
+//     wav_export <rom path> <frame count> <output path>
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use emulator_rs::audio::WavWriter;
+use emulator_rs::emulator::{self, EmuState};
+
+const SAMPLE_RATE: u32 = 44100;
+
+fn run(rom_path: &Path, frame_count: u32, output_path: &Path) -> emulator::Result<()> {
+    let rom_state = emulator::RomState::load(rom_path)?;
+    let mut emu_state = EmuState::from_rom(rom_state)?;
+
+    let mut wav_writer = WavWriter::new(BufWriter::new(File::create(output_path)?), SAMPLE_RATE)?;
+    for _ in 0 .. frame_count {
+        emu_state.run_to_next_vblank_with_sink(&mut wav_writer)?;
+    }
+    wav_writer.finish()?;
+
+    return Ok(());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("Usage: {} <rom path> <frame count> <output path>", args[0]);
+        process::exit(2);
+    }
+
+    let frame_count = match args[2].parse() {
+        Ok(count) => count,
+        Err(_) => {
+            eprintln!("Invalid frame count: {}", args[2]);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(Path::new(&args[1]), frame_count, Path::new(&args[3])) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
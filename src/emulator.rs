@@ -1,7 +1,11 @@
+use std::fmt;
 use std::io;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::apu::Apu;
+use crate::audio::AudioSink;
+use crate::mapper::{self, Mapper};
 use crate::opcodes;
 use crate::opcodes::Mnemonic;
 use crate::opcodes::AddressMode;
@@ -20,16 +24,31 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<Error> for ggez::error::GameError {
-    fn from(err: Error) -> Self {
-        Self::CustomError(format!("{:?}", err))
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "I/O error: {}", err),
+            Error::RomFileError(message) => write!(f, "ROM file error: {}", message),
+            Error::AddressError(message) => write!(f, "address error: {}", message),
+            Error::InvalidInstructionError(instruction) => write!(f, "invalid instruction {:02X}", instruction)
+        }
     }
 }
 
+impl std::error::Error for Error {}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 //--------------------------------------------------------------------------------
 
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+/// NTSC CPU clock rate in Hz, which is also the rate at which audio samples are produced.
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+//--------------------------------------------------------------------------------
+
 const PALETTE: [[u8; 4]; 64] = [
     [ 84,  84,  84, 255],  
     [  0,  30, 116, 255],  
@@ -97,63 +116,265 @@ const PALETTE: [[u8; 4]; 64] = [
     [  0,   0,   0, 255],
 ];
 
+// PPUMASK bits 5-7 emphasise red, green and blue by darkening the other two channels. The full palette has a copy of the
+// 64 colours for each combination of emphasis bits, indexed by emphasis << 6 | colour.
+// https://wiki.nesdev.com/w/index.php/NTSC_video#Color_Tint_Bits
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+fn make_full_palette() -> Vec<[u8; 4]> {
+    let mut result = Vec::with_capacity(512);
+    for emphasis in 0..8 {
+        for colour in PALETTE.iter() {
+            let mut pixel = *colour;
+            for (channel, value) in pixel.iter_mut().take(3).enumerate() {
+                if emphasis & !(1 << channel) != 0 {
+                    *value = (*value as f32 * EMPHASIS_ATTENUATION).round() as u8;
+                }
+            }
+            result.push(pixel);
+        }
+    }
+    return result;
+}
+
+/// How the two 1 KB nametables in PPU RAM appear in the four logical nametables at $2000-$2FFF. `Horizontal` and
+/// `Vertical` name the arrangement of the nametables, so `Horizontal` is what is usually called vertical mirroring.
+/// `FourScreen` uses 2 KB of extra RAM on the cartridge to make all four nametables independent.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NametableMirrorMode {
-    Horizontal, Vertical
+    Horizontal, Vertical, SingleScreenLower, SingleScreenUpper, FourScreen
 }
 
-pub struct RomState {
-    prg_rom : Vec<u8>,
-    chr_rom : Vec<u8>,
-    nametable_mirror_mode : NametableMirrorMode
+impl NametableMirrorMode {
+    /// Which physical 1 KB page backs each of the four logical nametables. Pages 0 and 1 are the PPU's own RAM, and
+    /// pages 2 and 3 are the cartridge's, from `Mapper::nametable_ram`.
+    pub fn pages(self) -> [usize; 4] {
+        match self {
+            NametableMirrorMode::Horizontal => [0, 1, 0, 1],
+            NametableMirrorMode::Vertical => [0, 0, 1, 1],
+            NametableMirrorMode::SingleScreenLower => [0, 0, 0, 0],
+            NametableMirrorMode::SingleScreenUpper => [1, 1, 1, 1],
+            NametableMirrorMode::FourScreen => [0, 1, 2, 3]
+        }
+    }
 }
 
-impl RomState {
-    pub fn load(path: &Path) -> Result<RomState> {
-        // Read entire file into a vector
-        let content = fs::read(path)?;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingMode {
+    Ntsc, Pal, MultiRegion, Dendy
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    PlayChoice10,
+    Extended(u8)
+}
+
+/// The contents of an iNES or NES 2.0 header. Sizes are in bytes. Fields that only NES 2.0 can express take their
+/// usual defaults for iNES files.
+#[derive(Debug, Clone)]
+pub struct RomHeader {
+    pub is_nes2: bool,
+    pub mapper_number: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub nametable_mirror_mode: NametableMirrorMode,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub four_screen: bool,
+    pub timing_mode: TimingMode,
+    pub console_type: ConsoleType,
+    pub misc_rom_count: u8,
+    pub default_expansion_device: u8
+}
+
+impl RomHeader {
+    // https://wiki.nesdev.com/w/index.php/INES
+    // https://wiki.nesdev.com/w/index.php/NES_2.0
+    pub fn parse(content: &[u8]) -> Result<RomHeader> {
+        if content.len() < 16 {
+            return Err(Error::RomFileError("file is too short".to_string()));
+        }
 
-        // Check header
         if content[0] != 0x4E || content[1] != 0x45 || content[2] != 0x53 || content[3] != 0x1A {
             return Err(Error::RomFileError("header bytes are incorrect".to_string()));
         }
 
-        // Check NES 2.0 format
-        if content[7] & 0x0C == 0x08 {
-            return Err(Error::RomFileError("NES 2.0 format is not yet supported".to_string()));
+        let is_nes2 = content[7] & 0x0C == 0x08;
+
+        // Get nametable mirror mode
+        let nametable_mirror_mode = if content[6] & 8 != 0 {
+            NametableMirrorMode::FourScreen
+        } else if content[6] & 1 != 0 {
+            NametableMirrorMode::Horizontal
+        } else {
+            NametableMirrorMode::Vertical
+        };
+
+        let console_type = match content[7] & 3 {
+            0 => ConsoleType::Nes,
+            1 if is_nes2 => ConsoleType::VsSystem { ppu_type: content[13] & 0x0F, hardware_type: content[13] >> 4 },
+            1 => ConsoleType::VsSystem { ppu_type: 0, hardware_type: 0 },
+            2 => ConsoleType::PlayChoice10,
+            _ => ConsoleType::Extended(if is_nes2 { content[13] & 0x0F } else { 0 })
+        };
+
+        let mut result = RomHeader {
+            is_nes2,
+            mapper_number: ((content[6] >> 4) | (content[7] & 0xF0)) as u16,
+            submapper: 0,
+            prg_rom_size: (content[4] as usize) * 16384,
+            chr_rom_size: (content[5] as usize) * 8192,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            nametable_mirror_mode,
+            has_battery: content[6] & 2 != 0,
+            has_trainer: content[6] & 4 != 0,
+            four_screen: content[6] & 8 != 0,
+            timing_mode: TimingMode::Ntsc,
+            console_type,
+            misc_rom_count: 0,
+            default_expansion_device: 0
+        };
+
+        if is_nes2 {
+            result.mapper_number |= ((content[8] & 0x0F) as u16) << 8;
+            result.submapper = content[8] >> 4;
+            result.prg_rom_size = RomHeader::get_nes2_rom_size(content[4], content[9] & 0x0F, 16384)?;
+            result.chr_rom_size = RomHeader::get_nes2_rom_size(content[5], content[9] >> 4, 8192)?;
+            result.prg_ram_size = RomHeader::get_nes2_ram_size(content[10] & 0x0F);
+            result.prg_nvram_size = RomHeader::get_nes2_ram_size(content[10] >> 4);
+            result.chr_ram_size = RomHeader::get_nes2_ram_size(content[11] & 0x0F);
+            result.chr_nvram_size = RomHeader::get_nes2_ram_size(content[11] >> 4);
+            result.timing_mode = match content[12] & 3 {
+                0 => TimingMode::Ntsc,
+                1 => TimingMode::Pal,
+                2 => TimingMode::MultiRegion,
+                _ => TimingMode::Dendy
+            };
+            result.misc_rom_count = content[14] & 3;
+            result.default_expansion_device = content[15] & 0x3F;
+        } else {
+            // Some old dumping tools wrote junk such as "DiskDude!" over bytes 7-15, in which case only bytes 4-6 can be
+            // trusted, and everything else takes its default
+            let prg_ram_size = if content[12 .. 16].iter().any(|&b| b != 0) {
+                result.mapper_number &= 0x0F;
+                result.console_type = ConsoleType::Nes;
+                8192
+            } else {
+                if content[9] & 1 != 0 {
+                    result.timing_mode = TimingMode::Pal;
+                }
+
+                // A size of 0 means 8 KB, for compatibility
+                (content[8].max(1) as usize) * 8192
+            };
+            if result.has_battery {
+                result.prg_nvram_size = prg_ram_size;
+            } else {
+                result.prg_ram_size = prg_ram_size;
+            }
+
+            if result.chr_rom_size == 0 {
+                result.chr_ram_size = 8192;
+            }
         }
 
-        // Get ROM data sizes
-        let prg_size = (content[4] as usize) * 16384;
-        let chr_size = (content[5] as usize) * 8192;
+        return Ok(result);
+    }
 
-        // Get mapper number
-        let mapper = (content[6] >> 4) | (content[7] & 0xF0);
-        if mapper != 0 {
-            return Err(Error::RomFileError(format!("mapper {} is not supported", mapper)));
+    fn get_nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize> {
+        if msb == 0x0F {
+            // Exponent-multiplier notation: 2^E * (MM*2+1) bytes. E can be up to 63, which is far bigger than any real ROM
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = ((lsb & 3) as usize) * 2 + 1;
+            return 1usize.checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .ok_or_else(|| Error::RomFileError("ROM size in header is too large".to_string()));
+        } else {
+            return Ok((((msb as usize) << 8) | lsb as usize) * unit);
         }
+    }
 
-        // Get nametable mirror mode
-        let nametable_mirror_mode = if content[6] & 1 != 0 { NametableMirrorMode::Horizontal } else { NametableMirrorMode::Vertical };
+    fn get_nes2_ram_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+}
+
+pub struct RomState {
+    pub(crate) header : RomHeader,
+    pub(crate) prg_rom : Vec<u8>,
+    pub(crate) chr : Vec<u8>,
+    pub(crate) chr_is_ram : bool,
+    pub(crate) prg_ram : Vec<u8>
+}
+
+impl RomState {
+    pub fn load(path: &Path) -> Result<RomState> {
+        // Read entire file into a vector
+        let content = fs::read(path)?;
+        return RomState::from_bytes(&content);
+    }
+
+    pub fn from_bytes(content: &[u8]) -> Result<RomState> {
+        let header = RomHeader::parse(content)?;
+        let prg_size = header.prg_rom_size;
+        let chr_size = header.chr_rom_size;
+
+        // The trainer, if present, sits between the header and PRG ROM
+        let trainer_size = if header.has_trainer { 512 } else { 0 };
+        let prg_start: usize = 16 + trainer_size;
 
-        // Check for things we don't support
-        if content[6] & 2 != 0 {
-            return Err(Error::RomFileError("Battery-backed RAM is not supported".to_string()));
+        if prg_size == 0 {
+            return Err(Error::RomFileError("there is no PRG ROM".to_string()));
         }
 
-        if content[6] & 4 != 0 {
-            return Err(Error::RomFileError("Trainer is not supported".to_string()));
+        let too_short = || Error::RomFileError("file is too short for the ROM sizes in the header".to_string());
+        let chr_start = prg_start.checked_add(prg_size).ok_or_else(too_short)?;
+        if content.len() < chr_start.checked_add(chr_size).ok_or_else(too_short)? {
+            return Err(too_short());
         }
 
-        if content[6] & 8 != 0 {
-            return Err(Error::RomFileError("Four-screen VRAM is not supported".to_string()));
+        // Boards with no CHR ROM have CHR RAM instead, at least 8 KB of it
+        let chr_is_ram = chr_size == 0;
+        let chr = if chr_is_ram {
+            vec![0; (header.chr_ram_size + header.chr_nvram_size).max(8192)]
+        } else {
+            content[chr_start .. chr_start+chr_size].to_vec()
+        };
+
+        // Battery-backed and volatile work RAM share the same address range, so are treated as one
+        let mut prg_ram = vec![0; header.prg_ram_size + header.prg_nvram_size];
+
+        // The trainer is loaded into $7000-$71FF, so needs a full 8 KB of work RAM
+        if header.has_trainer {
+            if prg_ram.len() < 8192 {
+                prg_ram.resize(8192, 0);
+            }
+            prg_ram[0x1000 .. 0x1200].copy_from_slice(&content[16 .. prg_start]);
         }
 
         return Ok(RomState {
-            prg_rom: content[16 .. 16+prg_size].to_vec(),
-            chr_rom: content[16+prg_size .. 16+prg_size+chr_size].to_vec(),
-            nametable_mirror_mode
+            prg_rom: content[prg_start .. chr_start].to_vec(),
+            chr,
+            chr_is_ram,
+            prg_ram,
+            header
         });
     }
+
+    pub fn header(&self) -> &RomHeader {
+        &self.header
+    }
 }
 
 //--------------------------------------------------------------------------------
@@ -173,7 +394,24 @@ struct SpriteData {
     y: u8,
     tile: u8,
     attributes: u8,
-    x: u8
+    x: u8,
+    pattern: [u8; 2] // the row to draw, fetched during dots 257-320 with any horizontal flip already applied
+}
+
+// https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+#[derive(PartialEq, Clone, Copy)]
+enum SpriteEvaluationPhase {
+    Searching, // filling secondary OAM
+    Overflow,  // secondary OAM is full, looking for a ninth sprite
+    Done       // all of OAM has been checked, or the overflow flag has been set
+}
+
+// https://wiki.nesdev.com/w/index.php/CPU_interrupts
+#[derive(PartialEq, Clone, Copy)]
+enum Interrupt {
+    Nmi,
+    Irq,
+    Brk
 }
 
 #[derive(Default)]
@@ -183,10 +421,15 @@ pub struct JoypadState {
 }
 
 pub struct EmuState {
-    pub rom_state : RomState,
+    rom_header : RomHeader,
+    mapper : Box<dyn Mapper>,
+    save_path : Option<PathBuf>,
+    saved_prg_ram : Vec<u8>,
     ram : [u8; 2048],
     cycle_count : u64,
     last_ppu_cycle : u64,
+    ppu_dot_count: u64, // PPU dots since power on, for mappers that time accesses to PPU memory
+    last_apu_cycle : u64,
     pub program_counter: u16,
     pub stack_pointer: u8,
     cpu_flags: CpuFlags,
@@ -194,7 +437,15 @@ pub struct EmuState {
     pub reg_x: u8,
     pub reg_y: u8,
 
-    pub joypad1: JoypadState,
+    nmi_line: bool,
+    nmi_pending: bool,
+    nmi_serviced: bool,
+    vblank_started: bool, // set when the PPU enters VBlank, whether or not NMI is enabled
+    pending_interrupt: Option<Interrupt>,
+
+    joypad1: JoypadState,
+
+    apu: Apu,
 
     ppu_ctrl: u8,
     ppu_mask: u8,
@@ -202,66 +453,223 @@ pub struct EmuState {
     pub ppu_y: i32,
     pub ppu_x: i32,
     ppu_odd_frame: bool,
-    ppu_address: u16,
     ppu_data_read_buffer: u8,
     ppu_nametable_ram: [[u8; 1024]; 2],
     ppu_palette_ram: [u8; 32],
     ppu_oam_ram: [u8; 256],
-    ppu_scroll_x: u8,
-    ppu_scroll_y: u8,
-    ppu_scroll_latch: bool,
-    ppu_nmi_flag: bool,
+    ppu_oam_address: u8,
 
-    pub frame_buffer: Vec<u8>,
+    // Internal scroll and address registers shared by $2005, $2006 and rendering
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    ppu_v: u16, // current VRAM address: fine Y, nametable, coarse Y, coarse X
+    ppu_t: u16, // temporary VRAM address, copied into v during rendering
+    ppu_fine_x: u8,
+    ppu_write_toggle: bool,
+
+    frame_buffer: Vec<u8>,
+    full_palette: Vec<[u8; 4]>,
     sprites_this_scanline: Vec<SpriteData>,
-    x_in_bg_tile: u8,
-    y_in_bg_tile: u8,
-    nt_entry: u8,
-    attribute: u8,
+    sprite_limit_enabled: bool,
+
+    // Sprite evaluation for the next scanline, which steps through OAM over dots 65-256
+    sprites_next_scanline: Vec<SpriteData>, // secondary OAM
+    sprite_evaluation_phase: SpriteEvaluationPhase,
+    sprite_evaluation_n: usize, // sprite index
+    sprite_evaluation_m: usize, // byte index within the sprite
+    sprite_evaluation_bytes_to_copy: u8,
+    sprite_evaluation_read: u8, // the byte most recently read from OAM, which is what $2004 returns
+
+    // Background tile data, fetched 8 dots at a time and fed out through shift registers
+    bg_next_tile: u8,
+    bg_next_pattern: [u8; 2],
+    bg_next_attribute: u8,
+    bg_pattern_shift: [u16; 2],
+    bg_attribute_shift: [u16; 2],
 
 }
 
 impl EmuState {
     pub fn new(rom_path: &Path) -> Result<EmuState> {
+        let mut result = EmuState::from_rom(RomState::load(rom_path)?)?;
+        if result.rom_header.has_battery {
+            result.load_battery_ram(&rom_path.with_extension("sav"))?;
+        }
+        return Ok(result);
+    }
+
+    /// Load battery-backed cartridge RAM from `save_path` if the file exists, and remember the path for
+    /// `flush_battery_ram`.
+    pub fn load_battery_ram(&mut self, save_path: &Path) -> Result<()> {
+        if save_path.exists() {
+            let content = fs::read(save_path)?;
+            let prg_ram = self.mapper.prg_ram_mut();
+            let length = content.len().min(prg_ram.len());
+            prg_ram[.. length].copy_from_slice(&content[.. length]);
+        }
+
+        self.save_path = Some(save_path.to_path_buf());
+        self.saved_prg_ram = self.mapper.prg_ram().to_vec();
+        return Ok(());
+    }
+
+    /// Write battery-backed cartridge RAM to the save file, if it has changed since it was last loaded or written.
+    /// Frontends should call this on exit and periodically while running.
+    pub fn flush_battery_ram(&mut self) -> Result<()> {
+        if let Some(save_path) = &self.save_path {
+            let prg_ram = self.mapper.prg_ram();
+            if prg_ram != &self.saved_prg_ram[..] {
+                fs::write(save_path, prg_ram)?;
+                self.saved_prg_ram = prg_ram.to_vec();
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn from_rom(rom_state: RomState) -> Result<EmuState> {
         let mut result = EmuState {
-            rom_state: RomState::load(rom_path)?,
+            rom_header: rom_state.header.clone(),
+            mapper: mapper::create_mapper(rom_state)?,
+            save_path: None,
+            saved_prg_ram: Vec::new(),
             ram: [0; 2048],
             cycle_count: 0,
             last_ppu_cycle: 0,
-            program_counter: 0x8000,
-            stack_pointer: 0xFD,
+            ppu_dot_count: 0,
+            last_apu_cycle: 0,
+            program_counter: 0,
+            stack_pointer: 0,
             cpu_flags: Default::default(),
             reg_a: 0,
             reg_x: 0,
             reg_y: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            nmi_serviced: false,
+            vblank_started: false,
+            pending_interrupt: None,
             joypad1: Default::default(),
+            apu: Apu::new(),
             ppu_ctrl: 0,
             ppu_mask: 0,
             ppu_status: 0,
             ppu_x: 0,
             ppu_y: -1,
             ppu_odd_frame: false,
-            ppu_address: 0,
             ppu_data_read_buffer: 0,
             ppu_nametable_ram: [[0; 1024]; 2],
             ppu_palette_ram: [0; 32],
             ppu_oam_ram: [0; 256],
-            ppu_scroll_x: 0,
-            ppu_scroll_y: 0,
-            ppu_scroll_latch: false,
-            ppu_nmi_flag: false,
+            ppu_oam_address: 0,
+            ppu_v: 0,
+            ppu_t: 0,
+            ppu_fine_x: 0,
+            ppu_write_toggle: false,
             frame_buffer: Vec::new(),
+            full_palette: make_full_palette(),
             sprites_this_scanline: Vec::new(),
-            x_in_bg_tile: 0,
-            y_in_bg_tile: 0,
-            nt_entry: 0,
-            attribute: 0
+            sprite_limit_enabled: true,
+            sprites_next_scanline: Vec::new(),
+            sprite_evaluation_phase: SpriteEvaluationPhase::Done,
+            sprite_evaluation_n: 0,
+            sprite_evaluation_m: 0,
+            sprite_evaluation_bytes_to_copy: 0,
+            sprite_evaluation_read: 0,
+            bg_next_tile: 0,
+            bg_next_pattern: [0; 2],
+            bg_next_attribute: 0,
+            bg_pattern_shift: [0; 2],
+            bg_attribute_shift: [0; 2]
         };
-        result.cpu_flags.interrupt_disable = true;
-        result.frame_buffer.resize(256 * 240 * 4, 0);
+        result.frame_buffer.resize(SCREEN_WIDTH * SCREEN_HEIGHT * 4, 0);
+        result.power_on()?;
         return Ok(result);
     }
 
+    // https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    fn power_on(&mut self) -> Result<()> {
+        self.reg_a = 0;
+        self.reg_x = 0;
+        self.reg_y = 0;
+        self.set_flags_as_u8(0x00);
+
+        // The reset sequence decrements the stack pointer from 0 to $FD
+        self.stack_pointer = 0x00;
+
+        return self.run_reset_sequence();
+    }
+
+    /// Soft reset, as if the console's reset button was pressed. RAM and the A, X and Y registers are preserved.
+    pub fn reset(&mut self) -> Result<()> {
+        self.update_ppu()?;
+
+        // https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+        self.ppu_ctrl = 0;
+        self.ppu_mask = 0;
+        self.ppu_t = 0;
+        self.ppu_fine_x = 0;
+        self.ppu_write_toggle = false;
+        self.ppu_data_read_buffer = 0;
+        self.ppu_odd_frame = false;
+        self.nmi_line = false;
+        self.nmi_pending = false;
+        self.pending_interrupt = None;
+
+        self.update_apu()?;
+        self.apu.reset();
+
+        return self.run_reset_sequence();
+    }
+
+    fn run_reset_sequence(&mut self) -> Result<()> {
+        // The CPU goes through the motions of an interrupt, but the three stack pushes are turned into reads
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.cpu_flags.interrupt_disable = true;
+        self.program_counter = self.read_vector(0xFFFC)?;
+        self.cycle_count += 7;
+        return Ok(());
+    }
+
+    fn read_vector(&mut self, address: u16) -> Result<u16> {
+        let lo_byte = self.read_byte(address)?;
+        let hi_byte = self.read_byte(address + 1)?;
+        return Ok((lo_byte as u16) | ((hi_byte as u16) << 8));
+    }
+
+    /// The number of CPU cycles since power on.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    pub fn rom_header(&self) -> &RomHeader {
+        &self.rom_header
+    }
+
+    /// The most recently rendered frame, as 256x240 RGBA pixels.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    /// Remove and return the audio produced since the last call, as one sample per CPU cycle (see `CPU_CLOCK_RATE`) in
+    /// the range 0 to 1. If this isn't called regularly, only the most recent third of a second or so is kept.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    /// Set the state of the buttons on controller 1, in the order A, B, Select, Start, Up, Down, Left, Right.
+    pub fn set_joypad1_buttons(&mut self, buttons: [bool; 8]) {
+        self.joypad1.buttons = buttons;
+    }
+
+    /// With the limit disabled, every sprite on a scanline is drawn instead of only the first eight, which removes
+    /// flicker but breaks games that hide sprites deliberately. The overflow flag is unaffected.
+    pub fn set_sprite_limit_enabled(&mut self, enabled: bool) {
+        self.sprite_limit_enabled = enabled;
+    }
+
+    pub fn sprite_limit_enabled(&self) -> bool {
+        self.sprite_limit_enabled
+    }
+
     fn read_byte(&mut self, address: u16) -> Result<u8> {
         match address {
             // internal RAM
@@ -275,35 +683,48 @@ impl EmuState {
                 self.update_ppu()?;
                 let result = self.ppu_status;
                 self.ppu_status &= 0x7F; // clear VBLANK latch
-                self.ppu_address = 0; // clear address latch
-                self.ppu_scroll_latch = false;
+                self.update_nmi_line();
+                self.ppu_write_toggle = false;
                 return Ok(result);
             }
 
-            // PPU_DATA
-            0x2007 => {
+            // PPU_OAM_DATA
+            0x2004 => {
                 self.update_ppu()?;
+                if self.is_ppu_rendering() && self.ppu_y >= 0 {
+                    match self.ppu_x {
+                        // Secondary OAM is being cleared to $FF, and reads see the value being written
+                        1 ..= 64 => return Ok(0xFF),
 
-                // https://wiki.nesdev.com/w/index.php?title=PPU_registers#The_PPUDATA_read_buffer_.28post-fetch.29
-                // Palette data is not buffered, all other data is
-                if self.ppu_address >= 0x3F00 && self.ppu_address <= 0x3FFF {
-                    self.ppu_data_read_buffer = self.read_ppu_byte(self.ppu_address)?;
-                }
+                        // Reads see the byte that sprite evaluation is looking at
+                        65 ..= 256 => return Ok(self.sprite_evaluation_read),
 
-                let result = self.ppu_data_read_buffer;
-                self.ppu_data_read_buffer = self.read_ppu_byte(self.ppu_address)?;
+                        _ => {}
+                    }
+                }
+                return Ok(self.ppu_oam_ram[self.ppu_oam_address as usize]);
+            }
 
-                if self.ppu_ctrl & 4 != 0 {
-                    self.ppu_address += 32;
-                } else {
-                    self.ppu_address += 1;
+            // PPU_DATA
+            0x2007 => {
+                self.update_ppu()?;
+                if self.is_dmc_dma_on_this_cycle()? {
+                    self.read_ppu_data()?;
                 }
+                return self.read_ppu_data();
+            }
 
-                return Ok(result);
+            // APU status
+            0x4015 => {
+                self.update_apu()?;
+                Ok(self.apu.read_status())
             }
 
             // Joypad registers
             0x4016 => {
+                if self.is_dmc_dma_on_this_cycle()? {
+                    self.joypad1.next_button = (self.joypad1.next_button + 1) % 8;
+                }
                 let result = if self.joypad1.buttons[self.joypad1.next_button] { 1u8 } else { 0u8 };
                 self.joypad1.next_button = (self.joypad1.next_button + 1) % 8;
                 return Ok(result);
@@ -319,17 +740,64 @@ impl EmuState {
             // wrapping for PPU registers
             0x2008 ..= 0x3FFF => self.read_byte(address & 0x2007),
 
-            // program ROM
-            0x8000 ..= 0xFFFF => {
-                let index = (address - 0x8000) as usize;
-                Ok(self.rom_state.prg_rom[index % self.rom_state.prg_rom.len()])
-            },
+            // cartridge
+            0x4020 ..= 0xFFFF => self.mapper.read_cpu(address),
 
             // catch-all
             _ => Err(Error::AddressError("Invalid memory address".to_string()))
         }
     }
 
+    fn read_ppu_data(&mut self) -> Result<u8> {
+        // https://wiki.nesdev.com/w/index.php?title=PPU_registers#The_PPUDATA_read_buffer_.28post-fetch.29
+        // Palette data is not buffered, all other data is
+        let address = self.ppu_v & 0x3FFF;
+        if address >= 0x3F00 {
+            self.ppu_data_read_buffer = self.read_ppu_byte(address)?;
+        }
+
+        let result = self.ppu_data_read_buffer;
+        self.ppu_data_read_buffer = self.read_ppu_byte(address)?;
+        self.increment_ppu_address();
+
+        return Ok(result);
+    }
+
+    fn write_oam_data(&mut self, value: u8) {
+        // Bits 2-4 of the attribute byte don't exist, and read back as 0
+        let value = if self.ppu_oam_address & 3 == 2 { value & 0xE3 } else { value };
+        self.ppu_oam_ram[self.ppu_oam_address as usize] = value;
+        self.ppu_oam_address = self.ppu_oam_address.wrapping_add(1);
+    }
+
+    // True on the pre-render and visible scanlines when either background or sprites are enabled
+    fn is_ppu_rendering(&self) -> bool {
+        return self.ppu_mask & 0x18 != 0 && self.ppu_y < 240;
+    }
+
+    fn increment_ppu_address(&mut self) {
+        let increment = if self.ppu_ctrl & 4 != 0 { 32 } else { 1 };
+        self.ppu_v = (self.ppu_v + increment) & 0x7FFF;
+    }
+
+    // https://wiki.nesdev.com/w/index.php/APU_DMC#Conflict_with_controller_and_PPU_read
+    // When a DMC DMA halts the CPU during a read, the CPU repeats the read while it waits. Registers with side effects
+    // on read see an extra access: a joypad bit is skipped, or the PPU address is incremented twice.
+    // The DMA halts the CPU on the first read cycle after it becomes due, and every cycle of these instructions before
+    // the register read is also a read, so only a DMA that starts on the register read's own cycle conflicts with it.
+    fn is_dmc_dma_on_this_cycle(&mut self) -> Result<bool> {
+        // Catch up to the cycle before the read. A DMA on the way stalls the CPU and pushes the read later, so the
+        // target is re-read each iteration.
+        while self.last_apu_cycle + 1 < self.cycle_count {
+            self.step_apu()?;
+        }
+
+        if self.last_apu_cycle < self.cycle_count {
+            return self.step_apu();
+        }
+        return Ok(false);
+    }
+
     fn read_next_program_byte(&mut self) -> Result<u8> {
         let result = self.read_byte(self.program_counter)?;
         self.program_counter += 1;
@@ -351,6 +819,8 @@ impl EmuState {
             0x2000 => {
                 self.update_ppu()?;
                 self.ppu_ctrl = value;
+                self.ppu_t = (self.ppu_t & !0x0C00) | ((value as u16 & 0x03) << 10); // nametable select
+                self.update_nmi_line(); // enabling NMI during VBlank triggers one immediately
                 Ok(())
             }
 
@@ -364,50 +834,70 @@ impl EmuState {
             // PPU_OAM_ADDR
             0x2003 => {
                 self.update_ppu()?;
-                if value != 0 {
-                    Err(Error::AddressError("Nonzero OAM address not implemented".to_string()))
+                self.ppu_oam_address = value;
+                Ok(())
+            }
+
+            // PPU_OAM_DATA
+            0x2004 => {
+                self.update_ppu()?;
+                if self.is_ppu_rendering() {
+                    // The write is ignored, but OAMADDR gets a glitchy increment of its upper 6 bits
+                    // https://wiki.nesdev.com/w/index.php/PPU_registers#OAMDATA
+                    self.ppu_oam_address = self.ppu_oam_address.wrapping_add(4);
                 } else {
-                    Ok(())
+                    self.write_oam_data(value);
                 }
+                Ok(())
             }
 
             // PPU_SCROLL
             0x2005 => {
                 self.update_ppu()?;
-                if !self.ppu_scroll_latch {
-                    self.ppu_scroll_x = value;
+                if !self.ppu_write_toggle {
+                    // Coarse X and fine X
+                    self.ppu_t = (self.ppu_t & !0x001F) | (value as u16 >> 3);
+                    self.ppu_fine_x = value & 0x07;
                 } else {
-                    self.ppu_scroll_y = value;
+                    // Fine Y and coarse Y
+                    self.ppu_t = (self.ppu_t & !0x73E0) | ((value as u16 & 0x07) << 12) | ((value as u16 & 0xF8) << 2);
                 }
-                self.ppu_scroll_latch = !self.ppu_scroll_latch;
+                self.ppu_write_toggle = !self.ppu_write_toggle;
                 Ok(())
             }
 
             // PPU_ADDR
             0x2006 => {
                 self.update_ppu()?;
-                self.ppu_address = self.ppu_address << 8 | (value as u16);
+                if !self.ppu_write_toggle {
+                    // High byte, with bit 14 cleared
+                    self.ppu_t = (self.ppu_t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+                } else {
+                    self.ppu_t = (self.ppu_t & 0xFF00) | value as u16;
+                    self.ppu_v = self.ppu_t;
+
+                    // Outside rendering, v drives the PPU address bus, which some games use to clock the MMC3's scanline counter
+                    self.mapper.on_ppu_address(self.ppu_v & 0x3FFF, self.ppu_dot_count);
+                }
+                self.ppu_write_toggle = !self.ppu_write_toggle;
                 Ok(())
             }
 
             // PPU_DATA
             0x2007 => {
                 self.update_ppu()?;
-                self.write_ppu_byte(self.ppu_address, value)?;
-                if self.ppu_ctrl & 4 != 0 {
-                    self.ppu_address += 32;
-                }
-                else {
-                    self.ppu_address += 1;
-                }
+                self.write_ppu_byte(self.ppu_v & 0x3FFF, value)?;
+                self.increment_ppu_address();
                 Ok(())
             }
 
             // OAM_DMA
             0x4014 => {
+                // The copy starts at OAMADDR and wraps around, leaving OAMADDR where it started
                 let base_address = (value as u16) << 8;
                 for index in 0..256 {
-                    self.ppu_oam_ram[index] = self.read_byte(base_address + index as u16)?;
+                    let value = self.read_byte(base_address + index)?;
+                    self.write_oam_data(value);
                 }
 
                 if self.cycle_count % 2 == 1 {
@@ -420,8 +910,9 @@ impl EmuState {
             }
 
             // APU registers
-            0x4000 ..= 0x4013 | 0x4015 => {
-                // TODO
+            0x4000 ..= 0x4013 | 0x4015 | 0x4017 => {
+                self.update_apu()?;
+                self.apu.write_register(address, value);
                 Ok(())
             }
 
@@ -430,17 +921,15 @@ impl EmuState {
                 self.joypad1.next_button = 0;
                 Ok(())
             }
-            
-            0x4017 => {
-                // TODO
-                Ok(())
-            }
 
             // TODO: registers
 
             // wrapping for PPU registers
             0x2008 ..= 0x3FFF => self.write_byte(address & 0x2007, value),
 
+            // cartridge
+            0x4020 ..= 0xFFFF => self.mapper.write_cpu(address, value, self.cycle_count),
+
             // catch-all
             _ => Err(Error::AddressError("Invalid memory address".to_string()))
         }
@@ -461,7 +950,9 @@ impl EmuState {
         return self.ram[0x100 | self.stack_pointer as usize];
     }
 
-    fn get_operand_address(&mut self, address_mode: &AddressMode) -> Result<u16> {
+    // Indexed addressing takes an extra cycle to fix the high byte of the address when a page boundary is crossed.
+    // Instructions that write always take it, as the CPU can't undo a write to the wrong address.
+    fn get_operand_address(&mut self, address_mode: &AddressMode, writes_memory: bool) -> Result<u16> {
         match address_mode {
             AddressMode::ABS | AddressMode::ABX | AddressMode::ABY => {
                 self.cycle_count += 2;
@@ -475,8 +966,8 @@ impl EmuState {
                     _ => unreachable!()
                 };
                 let address = base_address.wrapping_add(offset as u16);
-                if (base_address >> 8) != (address >> 8) {
-                    self.cycle_count += 1; // page boundary crossed
+                if !matches!(address_mode, AddressMode::ABS) && (writes_memory || (base_address >> 8) != (address >> 8)) {
+                    self.cycle_count += 1;
                 }
                 return Ok(address);
             }
@@ -497,8 +988,8 @@ impl EmuState {
                 let address = base_address.wrapping_add(self.reg_y as u16);
 
                 self.cycle_count += 3;
-                if (base_address >> 8) != (address >> 8) {
-                    self.cycle_count += 1; // page boundary crossed
+                if writes_memory || (base_address >> 8) != (address >> 8) {
+                    self.cycle_count += 1;
                 }
                 return Ok(address);
             },
@@ -545,6 +1036,50 @@ impl EmuState {
         }
     }
 
+    fn add_with_carry(&mut self, other: u8) {
+        let (result_1, overflow_1) = self.reg_a.overflowing_add(other);
+        let (result, overflow) = result_1.overflowing_add(if self.cpu_flags.carry {1} else {0});
+        self.set_zero_negative_flags(result);
+
+        // http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html#:~:text=(M%5Eresult)%26(N%5Eresult)%260x80
+        self.cpu_flags.overflow = (self.reg_a ^ result) & (other ^ result) & 0x80 != 0;
+        self.cpu_flags.carry = overflow_1 || overflow;
+        self.reg_a = result;
+    }
+
+    fn compare(&mut self, reg: u8, value: u8) {
+        self.cpu_flags.carry = reg >= value;
+        self.cpu_flags.zero = reg == value;
+        self.cpu_flags.negative = reg.wrapping_sub(value) & 0x80 != 0;
+    }
+
+    // ASL, LSR, ROL or ROR, or the shift or rotate half of an unofficial combined instruction
+    fn shift_or_rotate(&mut self, mnemonic: &Mnemonic, old_value: u8) -> u8 {
+        let carry_bit: u8 = if self.cpu_flags.carry { 1 } else { 0 };
+        let (new_value, carry) = match mnemonic {
+            Mnemonic::ASL | Mnemonic::SLO => (old_value << 1, old_value & 0x80 != 0),
+            Mnemonic::LSR | Mnemonic::SRE => (old_value >> 1, old_value & 0x01 != 0),
+            Mnemonic::ROL | Mnemonic::RLA => (old_value << 1 | carry_bit, old_value & 0x80 != 0),
+            Mnemonic::ROR | Mnemonic::RRA => (old_value >> 1 | carry_bit << 7, old_value & 0x01 != 0),
+            _ => unreachable!()
+        };
+
+        self.set_zero_negative_flags(new_value);
+        self.cpu_flags.carry = carry;
+        return new_value;
+    }
+
+    // Read-modify-write instructions take two extra cycles, writing the unmodified value back before the result
+    fn read_modify_write(&mut self, address: u16, operation: impl FnOnce(&mut Self, u8) -> u8) -> Result<u8> {
+        let old_value = self.read_byte(address)?;
+        self.cycle_count += 1;
+        self.write_byte(address, old_value)?; // dummy write
+        let new_value = operation(self, old_value);
+        self.cycle_count += 1;
+        self.write_byte(address, new_value)?;
+        return Ok(new_value);
+    }
+
     pub fn get_flags_as_u8(&self) -> u8 {
         let mut result: u8 = 0x20;
         if self.cpu_flags.carry             { result |= 0x01 }
@@ -565,36 +1100,107 @@ impl EmuState {
         self.cpu_flags.negative          = value & 0x80 != 0;
     }
 
-    fn jump_to_interrupt(&mut self, address: u16) {
-        // Push return address to stack
+    // The PPU's NMI output is active while the VBlank flag and the NMI enable bit are both set. The CPU detects the
+    // falling edge of /NMI, so the interrupt is latched when the output becomes active and then stays pending until
+    // it is serviced.
+    fn update_nmi_line(&mut self) {
+        let nmi_line = self.ppu_status & 0x80 != 0 && self.ppu_ctrl & 0x80 != 0;
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
+    }
+
+    // IRQ sources share an open-collector line, so the CPU sees an IRQ while any of them asserts it
+    fn irq_line(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+
+    // The CPU polls for interrupts at the end of each instruction, and runs the interrupt sequence instead of fetching
+    // the next opcode if one is found. NMI takes priority, and IRQ is masked by the I flag.
+    fn poll_interrupts(&mut self, interrupt_disable: bool) -> Result<()> {
+        self.update_ppu()?;
+        self.update_apu()?;
+
+        if self.nmi_pending {
+            self.pending_interrupt = Some(Interrupt::Nmi);
+        } else if self.irq_line() && !interrupt_disable {
+            self.pending_interrupt = Some(Interrupt::Irq);
+        }
+        return Ok(());
+    }
+
+    // Pushes the return address and status, sets the I flag and jumps through the vector, taking 7 cycles in total.
+    // For BRK, the first two cycles are the opcode fetch and the read of its padding byte; for hardware interrupts
+    // they are dummy fetches.
+    fn run_interrupt_sequence(&mut self, interrupt: Interrupt) -> Result<()> {
         self.push_to_stack((self.program_counter >> 8) as u8);
         self.push_to_stack((self.program_counter & 0xFF) as u8);
+        self.cycle_count += 2;
+
+        // An NMI that arrives before the vector is fetched hijacks a BRK or IRQ, which then jumps to the NMI handler
+        self.update_ppu()?;
+        let is_nmi = interrupt == Interrupt::Nmi || self.nmi_pending;
+        if is_nmi {
+            self.nmi_pending = false;
+            self.nmi_serviced = true;
+        }
 
-        // Push processor flags to stack
-        self.push_to_stack(self.get_flags_as_u8());
+        // The B flag only exists on the stack, where it tells the handler whether it was entered by BRK
+        let flags = self.get_flags_as_u8() | if interrupt == Interrupt::Brk { 0x10 } else { 0x00 };
+        self.push_to_stack(flags);
+        self.cpu_flags.interrupt_disable = true;
+        self.cycle_count += 1;
 
-        // Jump
-        self.program_counter = address;
+        self.program_counter = self.read_vector(if is_nmi { 0xFFFA } else { 0xFFFE })?;
+        self.cycle_count += 2;
+
+        return Ok(());
     }
 
+    /// Run until the CPU has entered the NMI handler, which is the start of VBlank if the game has NMIs enabled.
     pub fn run_to_next_nmi(&mut self) -> Result<()> {
-        if self.ppu_nmi_flag {
-            self.ppu_nmi_flag = false;
-            let interrupt_lo = self.read_byte(0xFFFA)?;
-            let interrupt_hi = self.read_byte(0xFFFB)?;
-            let interrupt_address = (interrupt_lo as u16) | ((interrupt_hi as u16) << 8);
-            self.jump_to_interrupt(interrupt_address);
+        self.nmi_serviced = false;
+
+        loop {
+            self.run_one_instruction()?;
+            if self.nmi_serviced { return Ok(()); }
         }
+    }
+
+    /// Run until the PPU enters VBlank. Unlike `run_to_next_nmi`, this finishes once per frame even when the game has
+    /// NMIs disabled.
+    pub fn run_to_next_vblank(&mut self) -> Result<()> {
+        self.vblank_started = false;
 
         loop {
             self.run_one_instruction()?;
-            if self.ppu_nmi_flag { return Ok(()); }
+            self.update_ppu()?; // the PPU is otherwise only brought up to date when something needs it
+            if self.vblank_started { return Ok(()); }
         }
     }
 
+    /// Run to the next VBlank, as `run_to_next_vblank`, and pass the audio produced since the samples were last taken to
+    /// `sink`.
+    pub fn run_to_next_vblank_with_sink(&mut self, sink: &mut dyn AudioSink) -> Result<()> {
+        self.run_to_next_vblank()?;
+        self.update_apu()?;
+        return sink.write_samples(&self.apu.take_samples());
+    }
+
     pub fn run_one_instruction(&mut self) -> Result<()> {
         let debug_print = false;
 
+        // The first instruction of the handler always runs before interrupts are polled again
+        if let Some(interrupt) = self.pending_interrupt.take() {
+            self.cycle_count += 2;
+            return self.run_interrupt_sequence(interrupt);
+        }
+
+        // CLI, SEI and PLP change the I flag after the interrupt poll, so their effect is delayed by one instruction
+        let interrupt_disable_before = self.cpu_flags.interrupt_disable;
+        let mut skip_interrupt_poll = false;
+
         let instruction = self.read_next_program_byte()?;
         let opcode = opcodes::decode(instruction);
 
@@ -604,13 +1210,13 @@ impl EmuState {
 
         self.cycle_count += 2;
 
-        let operand_address = self.get_operand_address(&opcode.address_mode)?;
+        let operand_address = self.get_operand_address(&opcode.address_mode, opcode.mnemonic.writes_memory())?;
 
         if debug_print {
             print!("  {:04X}", operand_address);
             match operand_address {
                 0x0000 ..= 0x1FFF | 0x8000 ..= 0xFFFF => println!(" ({:02X})", self.read_byte(operand_address)?),
-                _ => println!("")
+                _ => println!()
             }
         }
 
@@ -619,24 +1225,13 @@ impl EmuState {
 
             Mnemonic::ADC => {
                 let other = self.read_byte(operand_address)?;
-                let (result_1, overflow_1) = self.reg_a.overflowing_add(other);
-                let (result, overflow) = result_1.overflowing_add(if self.cpu_flags.carry {1} else {0});
-                self.set_zero_negative_flags(result);
-
-                // http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html#:~:text=(M%5Eresult)%26(N%5Eresult)%260x80
-                self.cpu_flags.overflow = (self.reg_a ^ result) & (other ^ result) & 0x80 != 0;
-                self.cpu_flags.carry = overflow_1 || overflow;
-                self.reg_a = result;
+                self.add_with_carry(other);
             }
 
             Mnemonic::SBC => {
+                // Subtracting is adding the one's complement, with carry acting as an inverted borrow
                 let other = self.read_byte(operand_address)?;
-                let (result_1, overflow_1) = self.reg_a.overflowing_sub(other);
-                let (result, overflow) = result_1.overflowing_sub(if self.cpu_flags.carry {0} else {1});
-                self.set_zero_negative_flags(result);
-                self.cpu_flags.overflow = (self.reg_a ^ result) & ((255 - other) ^ result) & 0x80 != 0;
-                self.cpu_flags.carry = !(overflow_1 || overflow);
-                self.reg_a = result;
+                self.add_with_carry(!other);
             }
             
             Mnemonic::AND => {
@@ -662,6 +1257,10 @@ impl EmuState {
                     self.cycle_count += 1;
                     if (self.program_counter >> 8) != (operand_address >> 8) {
                         self.cycle_count += 1; // crossed page boundary
+                    } else {
+                        // A taken branch that stays on the same page doesn't poll for interrupts on its extra cycle,
+                        // delaying any interrupt that arrives during the branch until after the next instruction
+                        skip_interrupt_poll = true;
                     }
 
                     self.program_counter = operand_address;
@@ -676,10 +1275,10 @@ impl EmuState {
             }
 
             Mnemonic::BRK => {
-                let interrupt_lo = self.read_byte(0xFFFE)?;
-                let interrupt_hi = self.read_byte(0xFFFF)?;
-                let interrupt_address = (interrupt_lo as u16) | ((interrupt_hi as u16) << 8);
-                self.jump_to_interrupt(interrupt_address);
+                // BRK is two bytes long, and the byte after the opcode is skipped
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.run_interrupt_sequence(Interrupt::Brk)?;
+                skip_interrupt_poll = true;
             }
 
             Mnemonic::CLC => {
@@ -707,15 +1306,11 @@ impl EmuState {
                 };
 
                 let value = self.read_byte(operand_address)?;
-
-                self.cpu_flags.carry = reg >= value;
-                self.cpu_flags.zero = reg == value;
-                self.cpu_flags.negative = reg.wrapping_sub(value) & 0x80 != 0;
+                self.compare(reg, value);
             }
 
             Mnemonic::DEC => {
-                let value = self.read_byte(operand_address)?.wrapping_sub(1);
-                self.write_byte(operand_address, value)?;
+                let value = self.read_modify_write(operand_address, |_, old_value| old_value.wrapping_sub(1))?;
                 self.set_zero_negative_flags(value);
             }
 
@@ -736,8 +1331,7 @@ impl EmuState {
             }
 
             Mnemonic::INC => {
-                let value = self.read_byte(operand_address)?.wrapping_add(1);
-                self.write_byte(operand_address, value)?;
+                let value = self.read_modify_write(operand_address, |_, old_value| old_value.wrapping_add(1))?;
                 self.set_zero_negative_flags(value);
             }
 
@@ -752,7 +1346,10 @@ impl EmuState {
             }
 
             Mnemonic::JMP => {
-                self.cycle_count += 1;
+                match opcode.address_mode {
+                    AddressMode::IND => self.cycle_count += 1, // reading the high byte of the target
+                    _ => self.cycle_count -= 1 // there is no operand read after the address
+                }
                 self.program_counter = operand_address;
                 self.update_ppu()?;
             }
@@ -784,7 +1381,11 @@ impl EmuState {
             }
 
             Mnemonic::NOP => {
-                // Do nothing
+                // Unofficial NOPs with an operand read it, and discard the result
+                match opcode.address_mode {
+                    AddressMode::IMP | AddressMode::IMM => {}
+                    _ => { self.read_byte(operand_address)?; }
+                }
             }
 
             Mnemonic::ORA => {
@@ -801,6 +1402,7 @@ impl EmuState {
             Mnemonic::PHP => {
                 let flags = self.get_flags_as_u8() | 0x10;
                 self.push_to_stack(flags);
+                self.cycle_count += 1;
             }
 
             Mnemonic::PLA => {
@@ -812,32 +1414,15 @@ impl EmuState {
             Mnemonic::PLP => {
                 let flags = self.pull_from_stack();
                 self.set_flags_as_u8(flags);
+                self.cycle_count += 2;
             }
 
             Mnemonic::ASL | Mnemonic::LSR | Mnemonic::ROL | Mnemonic::ROR => {
-                let old_value = match opcode.address_mode {
-                    AddressMode::IMP => self.reg_a,
-                    _ => self.read_byte(operand_address)?
-                };
-                let carry_bit: u8 = if self.cpu_flags.carry { 1 } else { 0 };
-                let new_value = match opcode.mnemonic {
-                    Mnemonic::ASL => old_value << 1,
-                    Mnemonic::LSR => old_value >> 1,
-                    Mnemonic::ROL => old_value << 1 | carry_bit,
-                    Mnemonic::ROR => old_value >> 1 | carry_bit << 7,
-                    _ => unreachable!()
-                };
-                
-                self.set_zero_negative_flags(new_value);
-                self.cpu_flags.carry = match opcode.mnemonic {
-                    Mnemonic::ASL | Mnemonic::ROL => old_value & 0x80 != 0,
-                    Mnemonic::LSR | Mnemonic::ROR => old_value & 0x01 != 0,
-                    _ => unreachable!()
-                };
-
                 match opcode.address_mode {
-                    AddressMode::IMP => self.reg_a = new_value,
-                    _ => self.write_byte(operand_address, new_value)?
+                    AddressMode::IMP => self.reg_a = self.shift_or_rotate(&opcode.mnemonic, self.reg_a),
+                    _ => {
+                        self.read_modify_write(operand_address, |emu, old_value| emu.shift_or_rotate(&opcode.mnemonic, old_value))?;
+                    }
                 };
             }
 
@@ -916,31 +1501,116 @@ impl EmuState {
                 self.reg_a = self.reg_y;
                 self.set_zero_negative_flags(self.reg_a);
             }
+
+            // Unofficial opcodes
+
+            Mnemonic::ALR => {
+                let other = self.read_byte(operand_address)?;
+                self.reg_a = self.shift_or_rotate(&Mnemonic::LSR, self.reg_a & other);
+            }
+
+            Mnemonic::ANC => {
+                let other = self.read_byte(operand_address)?;
+                self.reg_a &= other;
+                self.set_zero_negative_flags(self.reg_a);
+                self.cpu_flags.carry = self.cpu_flags.negative;
+            }
+
+            Mnemonic::ARR => {
+                let other = self.read_byte(operand_address)?;
+                let carry_bit: u8 = if self.cpu_flags.carry { 1 } else { 0 };
+                self.reg_a = (self.reg_a & other) >> 1 | carry_bit << 7;
+                self.set_zero_negative_flags(self.reg_a);
+                self.cpu_flags.carry = self.reg_a & 0x40 != 0;
+                self.cpu_flags.overflow = ((self.reg_a >> 6) ^ (self.reg_a >> 5)) & 1 != 0;
+            }
+
+            Mnemonic::AXS => {
+                let other = self.read_byte(operand_address)?;
+                let reg = self.reg_a & self.reg_x;
+                self.compare(reg, other);
+                self.reg_x = reg.wrapping_sub(other);
+            }
+
+            Mnemonic::DCP => {
+                let value = self.read_modify_write(operand_address, |_, old_value| old_value.wrapping_sub(1))?;
+                self.compare(self.reg_a, value);
+            }
+
+            Mnemonic::ISC => {
+                let value = self.read_modify_write(operand_address, |_, old_value| old_value.wrapping_add(1))?;
+                self.add_with_carry(!value);
+            }
+
+            Mnemonic::LAX => {
+                self.reg_a = self.read_byte(operand_address)?;
+                self.reg_x = self.reg_a;
+                self.set_zero_negative_flags(self.reg_a);
+            }
+
+            Mnemonic::RLA | Mnemonic::SLO | Mnemonic::SRE | Mnemonic::RRA => {
+                let value = self.read_modify_write(operand_address, |emu, old_value| emu.shift_or_rotate(&opcode.mnemonic, old_value))?;
+                match opcode.mnemonic {
+                    Mnemonic::RLA => self.reg_a &= value,
+                    Mnemonic::SLO => self.reg_a |= value,
+                    Mnemonic::SRE => self.reg_a ^= value,
+                    Mnemonic::RRA => self.add_with_carry(value),
+                    _ => unreachable!()
+                }
+                self.set_zero_negative_flags(self.reg_a);
+            }
+
+            Mnemonic::SAX => {
+                self.write_byte(operand_address, self.reg_a & self.reg_x)?;
+            }
         };
 
-        return Ok(());
+        if skip_interrupt_poll {
+            return Ok(());
+        }
+        let interrupt_disable = match opcode.mnemonic {
+            Mnemonic::CLI | Mnemonic::SEI | Mnemonic::PLP => interrupt_disable_before,
+            _ => self.cpu_flags.interrupt_disable
+        };
+        return self.poll_interrupts(interrupt_disable);
     }
 
     // ----------------------------------------------------------------------------
 
-    fn read_ppu_byte(&self, address : u16) -> Result<u8> {
+    fn get_mirrored_nametable_index(&self, address: u16) -> usize {
+        let nametable_index = ((address & 0x0C00) >> 10) as usize;
+        assert!(nametable_index < 4);
+        return self.mapper.nametable_mirror_mode().pages()[nametable_index];
+    }
+
+    // Read or write PPU memory, putting the address on the PPU's address bus where the cartridge can see it
+    fn read_ppu_byte(&mut self, address: u16) -> Result<u8> {
+        self.mapper.on_ppu_address(address, self.ppu_dot_count);
+        return self.read_ppu_memory(address);
+    }
+
+    fn write_ppu_byte(&mut self, address: u16, value: u8) -> Result<()> {
+        self.mapper.on_ppu_address(address, self.ppu_dot_count);
+        return self.write_ppu_memory(address, value);
+    }
+
+    fn read_ppu_memory(&self, address : u16) -> Result<u8> {
         match address {
             // Pattern tables
-            0x0000 ..= 0x1FFF => Ok(self.rom_state.chr_rom[address as usize]),
+            0x0000 ..= 0x1FFF => self.mapper.read_ppu(address),
 
             // Nametables
             0x2000 ..= 0x2FFF => {
-                let nametable_index = ((address & 0x0C00) >> 10) as usize;
-                assert!(nametable_index < 4);
-                let mirrored_index = match self.rom_state.nametable_mirror_mode {
-                    NametableMirrorMode::Horizontal => nametable_index % 2,
-                    NametableMirrorMode::Vertical => nametable_index / 2
-                };
-                Ok(self.ppu_nametable_ram[mirrored_index][(address & 0x03FF) as usize])
+                let offset = (address & 0x03FF) as usize;
+                match self.get_mirrored_nametable_index(address) {
+                    page @ 0 ..= 1 => Ok(self.ppu_nametable_ram[page][offset]),
+                    page => self.mapper.nametable_ram().get((page - 2) * 1024 + offset).copied()
+                        .ok_or_else(|| Error::AddressError(format!("no cartridge nametable RAM at PPU address {:04X}", address)))
+                }
             }
 
             // Wrapping
-            0x3000 ..= 0x3EFF => self.read_ppu_byte(address - 0x1000),
+            0x3000 ..= 0x3EFF => self.read_ppu_memory(address - 0x1000),
 
             // Palette
             0x3F00 ..= 0x3F1F => match address { 
@@ -952,29 +1622,33 @@ impl EmuState {
             }
 
             // Wrapping
-            0x3F20 ..= 0x3FFF => self.read_ppu_byte(address & 0x3F1F),
+            0x3F20 ..= 0x3FFF => self.read_ppu_memory(address & 0x3F1F),
 
             // Default
             _ => Err(Error::AddressError(format!("invalid PPU address {:04X}", address)))
         }
     }
 
-    fn write_ppu_byte(&mut self, address : u16, value : u8) -> Result<()> {
+    fn write_ppu_memory(&mut self, address : u16, value : u8) -> Result<()> {
         match address {
+            // Pattern tables
+            0x0000 ..= 0x1FFF => self.mapper.write_ppu(address, value),
+
             // Nametables
             0x2000 ..= 0x2FFF => {
-                let nametable_index = ((address & 0x0C00) >> 10) as usize;
-                assert!(nametable_index < 4);
-                let mirrored_index = match self.rom_state.nametable_mirror_mode {
-                    NametableMirrorMode::Horizontal => nametable_index % 2,
-                    NametableMirrorMode::Vertical => nametable_index / 2
-                };
-                self.ppu_nametable_ram[mirrored_index][(address & 0x03FF) as usize] = value;
+                let offset = (address & 0x03FF) as usize;
+                match self.get_mirrored_nametable_index(address) {
+                    page @ 0 ..= 1 => self.ppu_nametable_ram[page][offset] = value,
+                    page => match self.mapper.nametable_ram_mut().get_mut((page - 2) * 1024 + offset) {
+                        Some(byte) => *byte = value,
+                        None => return Err(Error::AddressError(format!("no cartridge nametable RAM at PPU address {:04X}", address)))
+                    }
+                }
                 Ok(())
             }
 
             // Wrapping
-            0x3000 ..= 0x3EFF => self.write_ppu_byte(address - 0x1000, value),
+            0x3000 ..= 0x3EFF => self.write_ppu_memory(address - 0x1000, value),
 
             // Palette
             0x3F00 ..= 0x3F1F => {
@@ -986,61 +1660,304 @@ impl EmuState {
             }
 
             // Wrapping
-            0x3F20 ..= 0x3FFF => self.write_ppu_byte(address & 0x3F1F, value),
+            0x3F20 ..= 0x3FFF => self.write_ppu_memory(address & 0x3F1F, value),
 
             // Default
             _ => Err(Error::AddressError(format!("invalid PPU address {:04X}", address)))
         }
     }
 
-    fn get_pixel_from_pattern_table(&self, pt_base: u16, tile_index: u8, x: u8, y: u8) -> Result<u8> {
-        let plane_0_address = pt_base + ((tile_index as u16) << 4) + y as u16;
-        let plane_0_row = self.read_ppu_byte(plane_0_address)?;
-        let plane_1_row = self.read_ppu_byte(plane_0_address + 8)?;
-        let bit_0 = (plane_0_row >> (7-x)) & 1;
-        let bit_1 = (plane_1_row >> (7-x)) & 1;
-        return Ok(bit_0 | (bit_1 << 1));
+    // Address of plane 0 of a row of a sprite in secondary OAM, taking vertical flip into account
+    fn get_sprite_pattern_address(&self, sprite: &SpriteData) -> u16 {
+        let sprite_height = self.sprite_height();
+
+        // Vertical flip applies to the whole sprite, so in 8x16 mode it also swaps the two tiles
+        let mut row = self.ppu_y - sprite.y as i32;
+        if sprite.attributes & 0x80 != 0 {
+            row = sprite_height - 1 - row;
+        }
+
+        let (pattern_table_base, tile) = if sprite_height == 16 {
+            // 8x16 sprites take the pattern table from bit 0 of the tile index, and use an even/odd pair of tiles
+            // https://wiki.nesdev.com/w/index.php/PPU_OAM#Byte_1
+            let pattern_table_base = ((sprite.tile & 0x01) as u16) << 12;
+            let tile = (sprite.tile & 0xFE) + (row / 8) as u8;
+            row %= 8;
+            (pattern_table_base, tile)
+        } else {
+            (((self.ppu_ctrl & 0x08) as u16) << 9, sprite.tile)
+        };
+
+        return pattern_table_base + ((tile as u16) << 4) + (row & 7) as u16;
+    }
+
+    // Fetch one plane of a sprite's pattern for the next scanline. Empty slots in secondary OAM fetch tile $FF instead,
+    // and the result is discarded.
+    // https://wiki.nesdev.com/w/index.php/PPU_rendering#Cycles_257-320
+    fn fetch_sprite_pattern(&mut self, slot: usize, plane: usize) -> Result<()> {
+        let address = match self.sprites_this_scanline.get(slot) {
+            Some(sprite) => self.get_sprite_pattern_address(sprite),
+            None if self.sprite_height() == 16 => 0x1FF0,
+            None => (((self.ppu_ctrl & 0x08) as u16) << 9) + 0x0FF0
+        };
+
+        // Sprites over the limit of eight are only drawn as an enhancement, so aren't seen on the bus
+        let value = if slot < 8 {
+            self.read_ppu_byte(address + plane as u16 * 8)?
+        } else {
+            self.read_ppu_memory(address + plane as u16 * 8)?
+        };
+
+        if let Some(sprite) = self.sprites_this_scanline.get_mut(slot) {
+            sprite.pattern[plane] = if sprite.attributes & 0x40 != 0 { value.reverse_bits() } else { value };
+        }
+
+        Ok(())
+    }
+
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let row = self.ppu_y - y as i32;
+        return (0 .. self.sprite_height()).contains(&row);
+    }
+
+    // One read from OAM during sprite evaluation, which happens on every odd dot from 65 to 255. The writes to secondary
+    // OAM on the even dots in between aren't observable, so are folded into the reads.
+    // https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    fn step_sprite_evaluation(&mut self) {
+        let value = self.ppu_oam_ram[self.sprite_evaluation_n * 4 + self.sprite_evaluation_m];
+        self.sprite_evaluation_read = value;
+
+        if self.sprite_evaluation_bytes_to_copy > 0 {
+            // Rest of a sprite that is in range
+            self.sprite_evaluation_bytes_to_copy -= 1;
+            self.sprite_evaluation_m += 1;
+            if self.sprite_evaluation_m == 4 {
+                self.sprite_evaluation_m = 0;
+                self.next_sprite_evaluation_n();
+            }
+
+            if self.sprite_evaluation_bytes_to_copy == 0 {
+                match self.sprite_evaluation_phase {
+                    SpriteEvaluationPhase::Searching if self.sprites_next_scanline.len() == 8 => {
+                        self.sprite_evaluation_phase = SpriteEvaluationPhase::Overflow;
+                    }
+                    SpriteEvaluationPhase::Overflow => self.sprite_evaluation_phase = SpriteEvaluationPhase::Done,
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        match self.sprite_evaluation_phase {
+            SpriteEvaluationPhase::Searching => {
+                if self.sprite_in_range(value) {
+                    self.sprites_next_scanline.push(self.get_sprite_data(self.sprite_evaluation_n));
+                    self.sprite_evaluation_bytes_to_copy = 3;
+                    self.sprite_evaluation_m = 1;
+                } else {
+                    self.next_sprite_evaluation_n();
+                }
+            }
+
+            // With secondary OAM full, the PPU also increments the byte index m along with n whenever a sprite isn't in
+            // range. It therefore checks tile numbers, attributes and X coordinates as though they were Y coordinates,
+            // leading to both false positives and false negatives.
+            SpriteEvaluationPhase::Overflow => {
+                if self.sprite_in_range(value) {
+                    self.ppu_status |= 0x20;
+                    self.sprite_evaluation_bytes_to_copy = 3;
+                    self.sprite_evaluation_m += 1;
+                    if self.sprite_evaluation_m == 4 {
+                        self.sprite_evaluation_m = 0;
+                        self.next_sprite_evaluation_n();
+                    }
+                } else {
+                    self.sprite_evaluation_m = (self.sprite_evaluation_m + 1) % 4;
+                    self.next_sprite_evaluation_n();
+                }
+            }
+
+            // Nothing more is written, but the PPU carries on reading Y coordinates
+            SpriteEvaluationPhase::Done => {
+                self.sprite_evaluation_m = 0;
+                self.sprite_evaluation_n = (self.sprite_evaluation_n + 1) % 64;
+            }
+        }
+    }
+
+    fn next_sprite_evaluation_n(&mut self) {
+        self.sprite_evaluation_n += 1;
+        if self.sprite_evaluation_n == 64 {
+            self.sprite_evaluation_n = 0;
+            self.sprite_evaluation_phase = SpriteEvaluationPhase::Done;
+        }
     }
 
-    fn update_bg_tile(&mut self) -> Result<()> {
-        let mut nametable_base = (self.ppu_ctrl & 3) as u16 * 0x400 + 0x2000;
-        assert!(nametable_base == 0x2000 || nametable_base == 0x2400 || nametable_base == 0x2800 || nametable_base == 0x2C00);
+    // Move secondary OAM into the sprites drawn on the next scanline
+    fn finish_sprite_evaluation(&mut self) {
+        self.sprites_this_scanline = std::mem::take(&mut self.sprites_next_scanline);
 
-        // Position in nametable in pixels
-        let nt_x = (self.ppu_scroll_x as i32) + self.ppu_x - 1;
-        let nt_y = (self.ppu_scroll_y as i32) + self.ppu_y;
+        if !self.sprite_limit_enabled {
+            for n in 0..64 {
+                if self.sprite_in_range(self.ppu_oam_ram[n * 4]) && !self.sprites_this_scanline.iter().any(|sprite| sprite.index == n) {
+                    self.sprites_this_scanline.push(self.get_sprite_data(n));
+                }
+            }
+        }
+    }
 
-        // Nametable tile coordinates
-        let mut tile_x = nt_x / 8;
-        if tile_x >= 32 {
-            tile_x -= 32;
-            nametable_base ^= 0x400;
+    fn get_sprite_data(&self, index: usize) -> SpriteData {
+        return SpriteData {
+            index,
+            y: self.ppu_oam_ram[index * 4],
+            tile: self.ppu_oam_ram[index * 4 + 1],
+            attributes: self.ppu_oam_ram[index * 4 + 2],
+            x: self.ppu_oam_ram[index * 4 + 3],
+            pattern: [0; 2]
+        };
+    }
+
+    // PPUCTRL bit 5 selects 8x16 sprites
+    fn sprite_height(&self) -> i32 {
+        return if self.ppu_ctrl & 0x20 != 0 { 16 } else { 8 };
+    }
+
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Wrapping_around
+    fn increment_coarse_x(&mut self) {
+        if self.ppu_v & 0x001F == 31 {
+            self.ppu_v &= !0x001F;
+            self.ppu_v ^= 0x0400; // switch horizontal nametable
+        } else {
+            self.ppu_v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.ppu_v & 0x7000 != 0x7000 {
+            self.ppu_v += 0x1000; // fine Y
+            return;
         }
-        let mut tile_y = nt_y / 8;
-        if tile_y >= 32 {
-            tile_y -= 32;
-            nametable_base ^= 0x800;
+
+        self.ppu_v &= !0x7000;
+        let mut coarse_y = (self.ppu_v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.ppu_v ^= 0x0800; // switch vertical nametable
+        } else if coarse_y == 31 {
+            coarse_y = 0; // rows 30 and 31 are attribute data, and wrap without switching nametable
+        } else {
+            coarse_y += 1;
         }
+        self.ppu_v = (self.ppu_v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // The PPU spends 8 dots fetching each background tile: the nametable byte, the attribute byte, then the two pattern
+    // planes, each taking two dots
+    fn fetch_bg_tile(&mut self, step: i32) -> Result<()> {
+        let v = self.ppu_v;
+        match step {
+            1 => self.bg_next_tile = self.read_ppu_byte(0x2000 | (v & 0x0FFF))?,
 
-        // Get nametable data
-        self.nt_entry = self.read_ppu_byte(nametable_base + (tile_y * 32 + tile_x) as u16)?;
-        let attr_index = (tile_y / 4) * 8 + (tile_x / 4);
-        let attribute_byte = self.read_ppu_byte(nametable_base + 0x3C0 + attr_index as u16)?;
-        let attribute_idx = (tile_y % 4) / 2 * 2 + (tile_x % 4) / 2;
-        assert!(attribute_idx >= 0 && attribute_idx < 4);
-        self.attribute = (attribute_byte >> (attribute_idx * 2)) & 3;
+            3 => {
+                let attribute_address = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let attribute_byte = self.read_ppu_byte(attribute_address)?;
+                let attribute_shift = ((v >> 4) & 4) | (v & 2); // which quadrant of the 32x32 pixel area
+                self.bg_next_attribute = (attribute_byte >> attribute_shift) & 3;
+            }
 
-        self.x_in_bg_tile = (nt_x % 8) as u8;
-        self.y_in_bg_tile = (nt_y % 8) as u8;
+            5 | 7 => {
+                let pattern_table_base = ((self.ppu_ctrl & 0x10) as u16) << 8;
+                let fine_y = (v >> 12) & 7;
+                let plane = (step as usize - 5) / 2;
+                let address = pattern_table_base + ((self.bg_next_tile as u16) << 4) + fine_y + plane as u16 * 8;
+                self.bg_next_pattern[plane] = self.read_ppu_byte(address)?;
+            }
+
+            _ => {}
+        }
 
         Ok(())
     }
 
+    // Background fetches and scroll register updates, on the pre-render and visible scanlines while rendering is on
+    fn run_bg_pipeline(&mut self) -> Result<()> {
+        let x = self.ppu_x;
+
+        if (2 ..= 257).contains(&x) || (322 ..= 337).contains(&x) {
+            for plane in 0..2 {
+                self.bg_pattern_shift[plane] <<= 1;
+                self.bg_attribute_shift[plane] <<= 1;
+            }
+
+            // Load the tile fetched over the previous 8 dots into the low bytes
+            if (x - 1) % 8 == 0 {
+                for plane in 0..2 {
+                    self.bg_pattern_shift[plane] = (self.bg_pattern_shift[plane] & 0xFF00) | self.bg_next_pattern[plane] as u16;
+                    let attribute_bits = if self.bg_next_attribute & (1 << plane) != 0 { 0xFF } else { 0x00 };
+                    self.bg_attribute_shift[plane] = (self.bg_attribute_shift[plane] & 0xFF00) | attribute_bits;
+                }
+            }
+        }
+
+        // Tiles for dots 1-256 are fetched two tiles ahead, with the first two fetched at the end of the previous line
+        if (1 ..= 256).contains(&x) || (321 ..= 336).contains(&x) {
+            if x % 8 == 0 {
+                self.increment_coarse_x();
+            } else {
+                self.fetch_bg_tile(x % 8)?;
+            }
+        }
+
+        // Two unused nametable fetches end the line
+        if x == 337 || x == 339 {
+            self.read_ppu_byte(0x2000 | (self.ppu_v & 0x0FFF))?;
+        }
+
+        match (x, self.ppu_y) {
+            (256, _) => self.increment_y(),
+
+            // Copy horizontal position from t to v
+            (257, _) => self.ppu_v = (self.ppu_v & !0x041F) | (self.ppu_t & 0x041F),
+
+            // Copy vertical position from t to v
+            (280 ..= 304, -1) => self.ppu_v = (self.ppu_v & !0x7BE0) | (self.ppu_t & 0x7BE0),
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn update_apu(&mut self) -> Result<()> {
+        // The loop bound is re-read each iteration, as DMC DMA stalls add to the cycle count
+        while self.last_apu_cycle < self.cycle_count {
+            self.step_apu()?;
+        }
+        return Ok(());
+    }
+
+    // Run the APU for one CPU cycle, returning whether a DMC DMA started on it
+    fn step_apu(&mut self) -> Result<bool> {
+        self.apu.step();
+        self.last_apu_cycle += 1;
+
+        if let Some(address) = self.apu.dmc_dma_address() {
+            // Sample addresses are always in $8000-$FFFF, so the fetch goes straight to the cartridge
+            let value = self.mapper.read_cpu(address)?;
+            self.apu.complete_dmc_dma(value);
+            self.cycle_count += 4;
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
     fn update_ppu(&mut self) -> Result<()> {
         let n_cycles = (self.cycle_count - self.last_ppu_cycle) * 3;
         self.last_ppu_cycle = self.cycle_count;
     
         for _ in 0 .. n_cycles {
+            self.ppu_dot_count += 1;
+            self.mapper.on_ppu_cycle(self.ppu_x, self.ppu_y);
 
             // Cycle skipping on odd frames
             if self.ppu_y == -1 && self.ppu_x == 0 {
@@ -1050,37 +1967,59 @@ impl EmuState {
                 self.ppu_odd_frame = !self.ppu_odd_frame;
             }
 
+            if self.is_ppu_rendering() {
+                self.run_bg_pipeline()?;
+
+                // OAMADDR is reset while sprite tiles are fetched. Each of the eight sprites takes 8 dots, with the two
+                // pattern planes fetched on the last four
+                if (257 ..= 320).contains(&self.ppu_x) {
+                    self.ppu_oam_address = 0;
+
+                    let slot = (self.ppu_x - 257) as usize / 8;
+                    match (self.ppu_x - 257) % 8 {
+                        4 => self.fetch_sprite_pattern(slot, 0)?,
+                        6 => self.fetch_sprite_pattern(slot, 1)?,
+                        _ => {}
+                    }
+
+                    if self.ppu_x == 320 {
+                        for slot in 8 .. self.sprites_this_scanline.len() {
+                            self.fetch_sprite_pattern(slot, 0)?;
+                            self.fetch_sprite_pattern(slot, 1)?;
+                        }
+                    }
+                }
+
+                // Sprite evaluation for the next scanline. Nothing is evaluated on the pre-render line, so there are no
+                // sprites on line 0
+                if self.ppu_y >= 0 {
+                    if self.ppu_x == 65 {
+                        self.sprites_next_scanline.clear();
+                        self.sprite_evaluation_phase = SpriteEvaluationPhase::Searching;
+                        self.sprite_evaluation_n = 0;
+                        self.sprite_evaluation_m = 0;
+                        self.sprite_evaluation_bytes_to_copy = 0;
+                    }
+                    if (65 ..= 255).contains(&self.ppu_x) && self.ppu_x % 2 == 1 {
+                        self.step_sprite_evaluation();
+                    }
+                }
+            }
+
             match (self.ppu_x, self.ppu_y) {
                 // Pre-render
                 (1, -1) => {
-                    // Clear sprite 0 hit
-                    self.ppu_status &= !0x40;
-
-                    // Reset nametable index
-                    // This is probably not the right way to do things but 🤷‍♂️
-                    self.ppu_ctrl &= !0x03;
+                    // Clear VBlank, sprite 0 hit and sprite overflow
+                    self.ppu_status &= !0xE0;
+                    self.update_nmi_line();
                 }
 
-                // Beginning of each scanline
-                (0, 0 ..= 239) => {
-                    // Get sprites for this scanline
-                    self.sprites_this_scanline.clear();
-
-                    'check_sprites: for i in 0..64 {
-                        let sprite_y = (self.ppu_oam_ram[i * 4] as i32) + 1;
-                        if self.ppu_y >= sprite_y && self.ppu_y < sprite_y + 8 {
-                            self.sprites_this_scanline.push(SpriteData {
-                                index: i,
-                                y: self.ppu_oam_ram[i * 4],
-                                tile: self.ppu_oam_ram[i * 4 + 1],
-                                attributes: self.ppu_oam_ram[i * 4 + 2],
-                                x: self.ppu_oam_ram[i * 4 + 3],
-                            });
-
-                            if self.sprites_this_scanline.len() >= 8 {
-                                break 'check_sprites;
-                            }
-                        }
+                // Sprites for the next scanline are fetched from dot 257
+                (257, -1 ..= 239) => {
+                    if self.is_ppu_rendering() && self.ppu_y >= 0 {
+                        self.finish_sprite_evaluation();
+                    } else {
+                        self.sprites_this_scanline.clear();
                     }
                 }
 
@@ -1090,46 +2029,27 @@ impl EmuState {
                     let mut sprite_pixel = 0u8;
                     let mut sprite_in_front = true;
 
+                    // Bits 1 and 2 of PPUMASK show the background and sprites in the leftmost 8 pixels
+                    let in_left_columns = self.ppu_x <= 8;
+
                     // Background drawing
-                    if self.ppu_mask & 0x08 != 0 {
-                        // Update nametable data
-                        if self.ppu_x == 1 || self.x_in_bg_tile >= 8 {
-                            self.update_bg_tile()?;
-                        }
-                        
-                        // Get pattern table data
-                        let pattern_table_base = ((self.ppu_ctrl & 0x10) as u16) << 8;
-                        assert!(pattern_table_base == 0x0000 || pattern_table_base == 0x1000);
-                        let palette_index = self.get_pixel_from_pattern_table(pattern_table_base, self.nt_entry, self.x_in_bg_tile, self.y_in_bg_tile)?;
-
-                        if palette_index == 0 {
-                            bg_pixel = 0;
-                        } else {
-                            bg_pixel = palette_index + self.attribute * 4;
-                        }
+                    if self.ppu_mask & 0x08 != 0 && (self.ppu_mask & 0x02 != 0 || !in_left_columns) {
+                        let bit = 15 - self.ppu_fine_x;
+                        let palette_index = (((self.bg_pattern_shift[0] >> bit) & 1) | (((self.bg_pattern_shift[1] >> bit) & 1) << 1)) as u8;
+                        let attribute = (((self.bg_attribute_shift[0] >> bit) & 1) | (((self.bg_attribute_shift[1] >> bit) & 1) << 1)) as u8;
 
-                        self.x_in_bg_tile += 1;
+                        if palette_index != 0 {
+                            bg_pixel = palette_index + attribute * 4;
+                        }
                     }
 
                     // Sprite drawing
-                    if self.ppu_mask & 0x10 != 0 {
+                    if self.ppu_mask & 0x10 != 0 && (self.ppu_mask & 0x04 != 0 || !in_left_columns) {
                         'sprite_loop: for sprite in &self.sprites_this_scanline {
                             let sprite_x = sprite.x as i32;
-                            if self.ppu_x-1 >= sprite_x && self.ppu_x-1 < sprite_x + 8 {
-                                let pattern_table_base = ((self.ppu_ctrl & 0x08) as u16) << 8;
-                                assert!(pattern_table_base == 0x0000 || pattern_table_base == 0x1000);
-
-                                let mut px = self.ppu_x - 1 - sprite_x;
-                                if sprite.attributes & 0x40 != 0 {
-                                    px = 7 - px;
-                                }
-
-                                let mut py = self.ppu_y - 1 - sprite.y as i32;
-                                if sprite.attributes & 0x80 != 0 {
-                                    py = 7 - py;
-                                }
-
-                                let palette_index = self.get_pixel_from_pattern_table(pattern_table_base, sprite.tile, px as u8, py as u8)?;
+                            if self.ppu_x > sprite_x && self.ppu_x-1 < sprite_x + 8 {
+                                let bit = 7 - (self.ppu_x - 1 - sprite_x);
+                                let palette_index = ((sprite.pattern[0] >> bit) & 1) | (((sprite.pattern[1] >> bit) & 1) << 1);
 
                                 if palette_index == 0 {
                                     sprite_pixel = 0;
@@ -1139,7 +2059,8 @@ impl EmuState {
                                 }
 
                                 if sprite_pixel != 0 {
-                                    if sprite.index == 0 && bg_pixel != 0 {
+                                    // A clipped background pixel is transparent, so can't trigger a hit. Nor can the last pixel
+                                    if sprite.index == 0 && bg_pixel != 0 && self.ppu_x != 256 {
                                         self.ppu_status |= 0x40; // zero hit
                                     }
 
@@ -1159,21 +2080,29 @@ impl EmuState {
                         (_, _, false) => bg_pixel
                     };
 
-                    // Get palette colour
-                    let colour_index = self.ppu_palette_ram[palette_index as usize];
-                    let pixel = PALETTE[colour_index as usize];
+                    // Get palette colour, with greyscale keeping only the grey column of the palette
+                    let mut colour_index = self.ppu_palette_ram[palette_index as usize] & 0x3F;
+                    if self.ppu_mask & 0x01 != 0 {
+                        colour_index &= 0x30;
+                    }
+                    let emphasis = (self.ppu_mask & 0xE0) as usize >> 5;
+                    let pixel = self.full_palette[emphasis << 6 | colour_index as usize];
 
                     // Set the pixel in the frame buffer
                     let index = ((self.ppu_y * 256 + self.ppu_x - 1) * 4) as usize;
                     self.frame_buffer[index .. index+4].copy_from_slice(&pixel);
                 }
 
+                // Sprite pattern fetches for the next scanline begin
+                (260, -1 ..= 239) if self.ppu_mask & 0x18 != 0 => {
+                    self.mapper.on_scanline(self.ppu_y);
+                }
+
                 // VBlank flag
                 (1, 241) => {
                     self.ppu_status |= 0x80;
-                    if self.ppu_ctrl & 0x80 != 0 {
-                        self.ppu_nmi_flag = true;
-                    }
+                    self.vblank_started = true;
+                    self.update_nmi_line();
                 }
 
                 // All others
@@ -0,0 +1,16 @@
+//! NES emulator core. This crate has no dependency on any windowing or audio library; the ggez frontend in
+//! `main.rs` is built only with the `frontend` feature.
+
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+mod apu;
+pub mod audio;
+pub mod emulator;
+pub mod mapper;
+pub mod opcodes;
+
+// The nestest log parser keeps its original form, which newer lints object to, and prints fields it never checks
+#[cfg(test)]
+#[allow(dead_code, clippy::from_str_radix_10, clippy::needless_borrow)]
+mod tests;
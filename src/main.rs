@@ -1,23 +1,26 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::VecDeque;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use ggez::{Context, ContextBuilder, GameResult};
+use ggez::{Context, ContextBuilder, GameError, GameResult};
 use ggez::graphics::{self, Color, Image, DrawParam, FilterMode};
-use ggez::event::{self, EventHandler, KeyCode};
+use ggez::event::{self, EventHandler, KeyCode, KeyMods};
 use ggez::conf::{WindowMode, WindowSetup};
 use ggez::input::keyboard;
 use ggez::timer;
+use rodio::{OutputStream, Source};
 
-mod emulator;
-mod opcodes;
-
-#[cfg(test)]
-mod tests;
+use emulator_rs::audio::Resampler;
+use emulator_rs::emulator;
 
 fn main() {
     let scale_factor = 4;
     let window_mode = WindowMode {
-        width: (256 * scale_factor) as f32,
-        height: (240 * scale_factor) as f32,
+        width: (emulator::SCREEN_WIDTH * scale_factor) as f32,
+        height: (emulator::SCREEN_HEIGHT * scale_factor) as f32,
         ..Default::default()
     };
     let window_setup = WindowSetup {
@@ -40,10 +43,113 @@ fn main() {
     event::run(ctx, event_loop, my_game);
 }
 
+//--------------------------------------------------------------------------------
+
+// The NTSC NES runs at about 60.1 frames per second. The audio rate control below absorbs the difference.
+const EMULATION_FPS: u32 = 60;
+
+// The most frames to run in one update when catching up after a stall
+const MAX_FRAMES_PER_UPDATE: u32 = 4;
+
+const AUDIO_SAMPLE_RATE: u32 = 44100;
+
+// Enough queued audio to ride out a late frame, without adding noticeable latency
+const AUDIO_BUFFER_TARGET: usize = AUDIO_SAMPLE_RATE as usize / 20;
+
+// The most that dynamic rate control will stretch or squeeze the audio by, which is too small to hear as a pitch change
+const AUDIO_MAX_RATE_ADJUST: f64 = 0.005;
+
+type AudioBuffer = Arc<Mutex<VecDeque<f32>>>;
+
+// Feeds the audio device from the ring buffer that the emulator fills each frame
+struct AudioBufferSource {
+    buffer: AudioBuffer,
+    last_sample: f32
+}
+
+impl Iterator for AudioBufferSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // On underrun, hold the last sample, as dropping to zero would click
+        if let Some(sample) = self.buffer.lock().unwrap().pop_front() {
+            self.last_sample = sample;
+        }
+        return Some(self.last_sample);
+    }
+}
+
+impl Source for AudioBufferSource {
+    fn current_frame_len(&self) -> Option<usize> { None }
+    fn channels(&self) -> u16 { 1 }
+    fn sample_rate(&self) -> u32 { AUDIO_SAMPLE_RATE }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
+
+struct AudioOutput {
+    _stream: OutputStream, // playback stops when this is dropped
+    buffer: AudioBuffer,
+    resampler: Resampler,
+    resampled: Vec<f32>
+}
+
+impl AudioOutput {
+    fn new() -> Option<AudioOutput> {
+        let (stream, stream_handle) = match OutputStream::try_default() {
+            Ok(result) => result,
+            Err(err) => {
+                println!("Failed to open audio device: {}", err);
+                return None;
+            }
+        };
+
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let source = AudioBufferSource { buffer: buffer.clone(), last_sample: 0.0 };
+        if let Err(err) = stream_handle.play_raw(source) {
+            println!("Failed to start audio: {}", err);
+            return None;
+        }
+
+        return Some(AudioOutput {
+            _stream: stream,
+            buffer,
+            resampler: Resampler::new(emulator::CPU_CLOCK_RATE, AUDIO_SAMPLE_RATE as f64),
+            resampled: Vec::new()
+        });
+    }
+
+    fn queue_samples(&mut self, samples: &[f32], volume: f32) {
+        self.resampled.clear();
+        self.resampler.process(samples, &mut self.resampled);
+
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(self.resampled.iter().map(|sample| sample * volume));
+
+        // Dynamic rate control. Emulation is paced by the system timer, which never exactly matches the audio device's
+        // clock, so produce slightly more audio per frame when the buffer is running low and slightly less when it is
+        // filling up. This corrects the small drift between the two and settles the buffer at the target level.
+        let error = 1.0 - buffer.len() as f64 / AUDIO_BUFFER_TARGET as f64;
+        let adjust = error.clamp(-1.0, 1.0) * AUDIO_MAX_RATE_ADJUST;
+        self.resampler.set_output_rate(AUDIO_SAMPLE_RATE as f64 * (1.0 + adjust));
+
+        // After a long stall, such as the window being dragged, drop the backlog rather than playing it late
+        if buffer.len() > AUDIO_BUFFER_TARGET * 4 {
+            let excess = buffer.len() - AUDIO_BUFFER_TARGET;
+            buffer.drain(.. excess);
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------
+
 struct MyGame {
     emu_state: emulator::EmuState,
     frame_image: Image,
-    frame_count: u64
+    frame_count: u64,
+    reset_requested: bool,
+    audio: Option<AudioOutput>,
+    volume: f32,
+    muted: bool
 }
 
 impl MyGame {
@@ -53,10 +159,59 @@ impl MyGame {
         return MyGame {
             emu_state: emulator::EmuState::new(rom_path).unwrap(),
             frame_image: Image::solid(ctx, 256, Color::BLACK).expect("Failed to create image"),
-            frame_count: 0
+            frame_count: 0,
+            reset_requested: false,
+            audio: AudioOutput::new(),
+            volume: 1.0,
+            muted: false
         };
     }
 
+    fn save_battery_ram(&mut self) {
+        if let Err(err) = self.emu_state.flush_battery_ram() {
+            println!("Failed to save battery-backed RAM: {}", err);
+        }
+    }
+
+    fn change_volume(&mut self, delta: f32) {
+        self.volume = (self.volume + delta).clamp(0.0, 1.0);
+        self.muted = false;
+        println!("Volume {:.0}%", self.volume * 100.0);
+    }
+
+    fn run_frame(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let mut buttons = [false; 8];
+        for (keycode, index) in KEY_MAP {
+            buttons[index] = keyboard::is_key_pressed(ctx, keycode);
+        }
+        self.emu_state.set_joypad1_buttons(buttons);
+
+        if self.reset_requested {
+            self.reset_requested = false;
+            self.emu_state.reset().map_err(|err| GameError::CustomError(err.to_string()))?;
+        }
+
+        // Step by vblank rather than NMI, as some games never enable NMI
+        self.emu_state.run_to_next_vblank().map_err(|err| GameError::CustomError(err.to_string()))?;
+
+        let samples = self.emu_state.take_audio_samples();
+        if let Some(audio) = &mut self.audio {
+            let volume = if self.muted { 0.0 } else { self.volume };
+            audio.queue_samples(&samples, volume);
+        }
+
+        self.frame_count += 1;
+        if self.frame_count.is_multiple_of(60) {
+            println!("{} fps", timer::fps(ctx));
+        }
+
+        // Save battery-backed RAM every 10 seconds, in case we don't exit cleanly
+        if self.frame_count.is_multiple_of(600) {
+            self.save_battery_ram();
+        }
+
+        return Ok(());
+    }
 }
 
 const KEY_MAP: [(KeyCode, usize); 8] = [
@@ -71,19 +226,20 @@ const KEY_MAP: [(KeyCode, usize); 8] = [
 ];
 
 impl EventHandler<ggez::GameError> for MyGame {
-    fn update(&mut self, _ctx: &mut Context) -> GameResult<()> {
-        for (keycode, index) in KEY_MAP {
-            self.emu_state.joypad1.buttons[index] = keyboard::is_key_pressed(_ctx, keycode);
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        // Run emulated frames at the NES's own rate, however fast the display refreshes. After a stall, only catch up a
+        // few frames rather than fast-forwarding through all the lost time.
+        let mut frames_run = 0;
+        while timer::check_update_time(ctx, EMULATION_FPS) {
+            if frames_run < MAX_FRAMES_PER_UPDATE {
+                self.run_frame(ctx)?;
+                frames_run += 1;
+            }
         }
 
-        self.emu_state.run_to_next_nmi()?;
-
-        self.frame_image = Image::from_rgba8(_ctx, 256, 240, &self.emu_state.frame_buffer)?;
-        self.frame_image.set_filter(FilterMode::Nearest);
-
-        self.frame_count += 1;
-        if self.frame_count % 60 == 0 {
-            println!("{} fps", timer::fps(_ctx));
+        if frames_run > 0 {
+            self.frame_image = Image::from_rgba8(ctx, emulator::SCREEN_WIDTH as u16, emulator::SCREEN_HEIGHT as u16, self.emu_state.frame_buffer())?;
+            self.frame_image.set_filter(FilterMode::Nearest);
         }
 
         Ok(())
@@ -96,4 +252,31 @@ impl EventHandler<ggez::GameError> for MyGame {
         
         graphics::present(ctx)
     }
+
+    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods, repeat: bool) {
+        match keycode {
+            KeyCode::Escape => {
+                self.save_battery_ram();
+                event::quit(ctx);
+            }
+            KeyCode::R if !repeat => self.reset_requested = true,
+            KeyCode::M if !repeat => {
+                self.muted = !self.muted;
+                println!("{}", if self.muted { "Muted" } else { "Unmuted" });
+            }
+            KeyCode::L if !repeat => {
+                let enabled = !self.emu_state.sprite_limit_enabled();
+                self.emu_state.set_sprite_limit_enabled(enabled);
+                println!("Sprite limit {}", if enabled { "enabled" } else { "disabled" });
+            }
+            KeyCode::Minus | KeyCode::NumpadSubtract => self.change_volume(-0.1),
+            KeyCode::Equals | KeyCode::NumpadAdd => self.change_volume(0.1),
+            _ => {}
+        }
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.save_battery_ram();
+        false
+    }
 }
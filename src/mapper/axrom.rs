@@ -0,0 +1,87 @@
+use crate::emulator::{Result, Error, RomState, NametableMirrorMode};
+use crate::mapper::{self, Mapper};
+
+// https://wiki.nesdev.com/w/index.php/AxROM
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    bus_conflicts: bool,
+    bank_select: u8
+}
+
+impl Axrom {
+    pub fn new(rom_state: RomState, bus_conflicts: bool) -> Axrom {
+        return Axrom {
+            prg_rom: rom_state.prg_rom,
+            prg_ram: rom_state.prg_ram,
+            chr: rom_state.chr,
+            chr_is_ram: rom_state.chr_is_ram,
+            bus_conflicts,
+            bank_select: 0
+        };
+    }
+
+    fn get_prg_rom_index(&self, address: u16) -> usize {
+        let bank = (self.bank_select & 0x07) as usize;
+        let index = bank * 0x8000 + (address & 0x7FFF) as usize;
+        return index % self.prg_rom.len();
+    }
+}
+
+impl Mapper for Axrom {
+    fn read_cpu(&mut self, address: u16) -> Result<u8> {
+        match address {
+            0x6000 ..= 0x7FFF => Ok(mapper::read_prg_ram(&self.prg_ram, address)),
+
+            0x8000 ..= 0xFFFF => Ok(self.prg_rom[self.get_prg_rom_index(address)]),
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn write_cpu(&mut self, address: u16, value: u8, _cpu_cycle: u64) -> Result<()> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                mapper::write_prg_ram(&mut self.prg_ram, address, value);
+                Ok(())
+            }
+
+            0x8000 ..= 0xFFFF => {
+                let rom_byte = self.prg_rom[self.get_prg_rom_index(address)];
+                self.bank_select = mapper::apply_bus_conflict(self.bus_conflicts, value, rom_byte);
+                Ok(())
+            }
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn read_ppu(&self, address: u16) -> Result<u8> {
+        Ok(self.chr[address as usize])
+    }
+
+    fn write_ppu(&mut self, address: u16, value: u8) -> Result<()> {
+        if self.chr_is_ram {
+            self.chr[address as usize] = value;
+        }
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn nametable_mirror_mode(&self) -> NametableMirrorMode {
+        // Bit 4 selects which nametable is shown on all four screens
+        if self.bank_select & 0x10 == 0 {
+            NametableMirrorMode::SingleScreenLower
+        } else {
+            NametableMirrorMode::SingleScreenUpper
+        }
+    }
+}
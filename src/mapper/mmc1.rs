@@ -0,0 +1,175 @@
+use crate::emulator::{Result, Error, RomState, NametableMirrorMode};
+use crate::mapper::{self, Mapper};
+
+// https://wiki.nesdev.com/w/index.php/MMC1
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: Vec<u8>,
+
+    shift_register: u8,
+    last_write_cycle: Option<u64>,
+
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8
+}
+
+impl Mmc1 {
+    pub fn new(rom_state: RomState) -> Mmc1 {
+        return Mmc1 {
+            prg_rom: rom_state.prg_rom,
+            chr: rom_state.chr,
+            chr_is_ram: rom_state.chr_is_ram,
+            prg_ram: rom_state.prg_ram,
+            shift_register: 0x10,
+            last_write_cycle: None,
+            control: 0x0C, // PRG ROM bank mode 3 at power on
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0
+        };
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn get_prg_rom_index(&self, address: u16) -> usize {
+        // On 512 KB boards (SUROM), bit 4 of the CHR bank register selects which 256 KB half of PRG ROM is used
+        let outer_bank = if self.prg_rom.len() > 0x40000 { (self.chr_bank_0 & 0x10) as usize } else { 0 };
+        let last_bank = (self.prg_rom.len() / 0x4000 - 1) & 0x0F;
+        let selected_bank = (self.prg_bank & 0x0F) as usize;
+
+        let bank_16k = match ((self.control >> 2) & 3, address) {
+            // Switch 32 KB at $8000, ignoring the low bit of the bank number
+            (0 ..= 1, 0x8000 ..= 0xBFFF) => selected_bank & !1,
+            (0 ..= 1, _) => selected_bank | 1,
+
+            // Fix first bank at $8000 and switch 16 KB bank at $C000
+            (2, 0x8000 ..= 0xBFFF) => 0,
+            (2, _) => selected_bank,
+
+            // Fix last bank at $C000 and switch 16 KB bank at $8000
+            (_, 0x8000 ..= 0xBFFF) => selected_bank,
+            (_, _) => last_bank
+        };
+
+        let index = (outer_bank | bank_16k) * 0x4000 + (address & 0x3FFF) as usize;
+        return index % self.prg_rom.len();
+    }
+
+    fn get_chr_index(&self, address: u16) -> usize {
+        let index = if self.control & 0x10 == 0 {
+            // Switch 8 KB at a time, ignoring the low bit of the bank number
+            ((self.chr_bank_0 & 0x1E) as usize) * 0x1000 + address as usize
+        } else {
+            // Switch two separate 4 KB banks
+            let bank = if address < 0x1000 { self.chr_bank_0 } else { self.chr_bank_1 };
+            (bank as usize) * 0x1000 + (address & 0x0FFF) as usize
+        };
+
+        return index % self.chr.len();
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000 ..= 0x9FFF => self.control = value,
+            0xA000 ..= 0xBFFF => self.chr_bank_0 = value,
+            0xC000 ..= 0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_cpu(&mut self, address: u16) -> Result<u8> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                if self.prg_ram_enabled() {
+                    Ok(mapper::read_prg_ram(&self.prg_ram, address))
+                } else {
+                    Ok(0) // open bus
+                }
+            }
+
+            0x8000 ..= 0xFFFF => Ok(self.prg_rom[self.get_prg_rom_index(address)]),
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn write_cpu(&mut self, address: u16, value: u8, cpu_cycle: u64) -> Result<()> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                if self.prg_ram_enabled() {
+                    mapper::write_prg_ram(&mut self.prg_ram, address, value);
+                }
+                Ok(())
+            }
+
+            0x8000 ..= 0xFFFF => {
+                // Writes on consecutive cycles, such as the two writes of a read-modify-write instruction, are ignored
+                // after the first
+                let consecutive = match self.last_write_cycle {
+                    Some(last_cycle) => cpu_cycle <= last_cycle + 1,
+                    None => false
+                };
+                self.last_write_cycle = Some(cpu_cycle);
+                if consecutive {
+                    return Ok(());
+                }
+
+                if value & 0x80 != 0 {
+                    // Reset the shift register and switch to PRG ROM bank mode 3
+                    self.shift_register = 0x10;
+                    self.control |= 0x0C;
+                } else {
+                    // The register is full when the initial 1 bit reaches the bottom
+                    let full = self.shift_register & 1 != 0;
+                    self.shift_register = (self.shift_register >> 1) | ((value & 1) << 4);
+                    if full {
+                        self.write_register(address, self.shift_register);
+                        self.shift_register = 0x10;
+                    }
+                }
+
+                Ok(())
+            }
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn read_ppu(&self, address: u16) -> Result<u8> {
+        Ok(self.chr[self.get_chr_index(address)])
+    }
+
+    fn write_ppu(&mut self, address: u16, value: u8) -> Result<()> {
+        if self.chr_is_ram {
+            let index = self.get_chr_index(address);
+            self.chr[index] = value;
+        }
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn nametable_mirror_mode(&self) -> NametableMirrorMode {
+        // Modes 2 and 3 are vertical and horizontal mirroring respectively
+        match self.control & 3 {
+            0 => NametableMirrorMode::SingleScreenLower,
+            1 => NametableMirrorMode::SingleScreenUpper,
+            2 => NametableMirrorMode::Horizontal,
+            _ => NametableMirrorMode::Vertical
+        }
+    }
+}
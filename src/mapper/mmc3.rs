@@ -0,0 +1,220 @@
+use crate::emulator::{Result, Error, RomState, NametableMirrorMode};
+use crate::mapper::{self, Mapper};
+
+// https://wiki.nesdev.com/w/index.php/MMC3
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: Vec<u8>,
+    nametable_mirror_mode: NametableMirrorMode,
+    nametable_ram: Vec<u8>,
+
+    bank_select: u8,
+    bank_registers: [u8; 8],
+    prg_ram_enabled: bool,
+    prg_ram_write_protected: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12_low_since: Option<u64> // the PPU dot when A12 last went low, or None while it is high
+}
+
+// The counter only sees a rising edge on PPU A12 after A12 has been low for a few CPU cycles. This filters out the
+// brief dips between pattern fetches, and between the last background fetch of one scanline and the first of the next.
+// https://wiki.nesdev.com/w/index.php/MMC3#IRQ_Specifics
+const A12_LOW_FILTER_DOTS: u64 = 12;
+
+impl Mmc3 {
+    pub fn new(rom_state: RomState) -> Mmc3 {
+        return Mmc3 {
+            nametable_ram: mapper::make_nametable_ram(&rom_state),
+            prg_rom: rom_state.prg_rom,
+            chr: rom_state.chr,
+            chr_is_ram: rom_state.chr_is_ram,
+            prg_ram: rom_state.prg_ram,
+            nametable_mirror_mode: rom_state.header.nametable_mirror_mode,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            prg_ram_enabled: true,
+            prg_ram_write_protected: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12_low_since: None
+        };
+    }
+
+    fn get_prg_rom_index(&self, address: u16) -> usize {
+        let bank_count = self.prg_rom.len() / 0x2000;
+        let second_last_bank = bank_count - 2;
+        let prg_mode_1 = self.bank_select & 0x40 != 0;
+
+        let bank_8k = match (address, prg_mode_1) {
+            (0x8000 ..= 0x9FFF, false) => (self.bank_registers[6] & 0x3F) as usize,
+            (0x8000 ..= 0x9FFF, true) => second_last_bank,
+            (0xA000 ..= 0xBFFF, _) => (self.bank_registers[7] & 0x3F) as usize,
+            (0xC000 ..= 0xDFFF, false) => second_last_bank,
+            (0xC000 ..= 0xDFFF, true) => (self.bank_registers[6] & 0x3F) as usize,
+            (_, _) => bank_count - 1
+        };
+
+        let index = bank_8k * 0x2000 + (address & 0x1FFF) as usize;
+        return index % self.prg_rom.len();
+    }
+
+    fn get_chr_index(&self, address: u16) -> usize {
+        // With A12 inversion, the two 2 KB banks are at $1000 and the four 1 KB banks are at $0000
+        let address = if self.bank_select & 0x80 != 0 { address ^ 0x1000 } else { address };
+
+        let bank_1k = match address {
+            0x0000 ..= 0x07FF => (self.bank_registers[0] & 0xFE) as usize + ((address as usize >> 10) & 1),
+            0x0800 ..= 0x0FFF => (self.bank_registers[1] & 0xFE) as usize + ((address as usize >> 10) & 1),
+            0x1000 ..= 0x13FF => self.bank_registers[2] as usize,
+            0x1400 ..= 0x17FF => self.bank_registers[3] as usize,
+            0x1800 ..= 0x1BFF => self.bank_registers[4] as usize,
+            _ => self.bank_registers[5] as usize
+        };
+
+        let index = bank_1k * 0x400 + (address & 0x03FF) as usize;
+        return index % self.chr.len();
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_cpu(&mut self, address: u16) -> Result<u8> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                if self.prg_ram_enabled {
+                    Ok(mapper::read_prg_ram(&self.prg_ram, address))
+                } else {
+                    Ok(0) // open bus
+                }
+            }
+
+            0x8000 ..= 0xFFFF => Ok(self.prg_rom[self.get_prg_rom_index(address)]),
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn write_cpu(&mut self, address: u16, value: u8, _cpu_cycle: u64) -> Result<()> {
+        match (address, address & 1 == 0) {
+            (0x6000 ..= 0x7FFF, _) => {
+                if self.prg_ram_enabled && !self.prg_ram_write_protected {
+                    mapper::write_prg_ram(&mut self.prg_ram, address, value);
+                }
+            }
+
+            // Bank select
+            (0x8000 ..= 0x9FFF, true) => self.bank_select = value,
+
+            // Bank data
+            (0x8000 ..= 0x9FFF, false) => self.bank_registers[(self.bank_select & 7) as usize] = value,
+
+            // Mirroring: 0 is vertical, 1 is horizontal. Has no effect on four-screen boards
+            (0xA000 ..= 0xBFFF, true) => {
+                if self.nametable_mirror_mode != NametableMirrorMode::FourScreen {
+                    self.nametable_mirror_mode = if value & 1 == 0 { NametableMirrorMode::Horizontal } else { NametableMirrorMode::Vertical };
+                }
+            }
+
+            // PRG RAM protect
+            (0xA000 ..= 0xBFFF, false) => {
+                self.prg_ram_enabled = value & 0x80 != 0;
+                self.prg_ram_write_protected = value & 0x40 != 0;
+            }
+
+            // IRQ latch
+            (0xC000 ..= 0xDFFF, true) => self.irq_latch = value,
+
+            // IRQ reload
+            (0xC000 ..= 0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+
+            // IRQ disable, which also acknowledges any pending interrupt
+            (0xE000 ..= 0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+
+            // IRQ enable
+            (0xE000 ..= 0xFFFF, false) => self.irq_enabled = true,
+
+            _ => return Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+
+        Ok(())
+    }
+
+    fn read_ppu(&self, address: u16) -> Result<u8> {
+        Ok(self.chr[self.get_chr_index(address)])
+    }
+
+    fn write_ppu(&mut self, address: u16, value: u8) -> Result<()> {
+        if self.chr_is_ram {
+            let index = self.get_chr_index(address);
+            self.chr[index] = value;
+        }
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn nametable_mirror_mode(&self) -> NametableMirrorMode {
+        self.nametable_mirror_mode
+    }
+
+    fn nametable_ram(&self) -> &[u8] {
+        &self.nametable_ram
+    }
+
+    fn nametable_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.nametable_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn on_ppu_address(&mut self, address: u16, ppu_dot: u64) {
+        // The counter is clocked by filtered rising edges on PPU A12. With background tiles at $0000 and sprites at
+        // $1000 there is one per scanline when sprite fetches begin, and with them the other way round there is one
+        // when background fetches for the next scanline begin.
+        if address & 0x1000 == 0 {
+            if self.a12_low_since.is_none() {
+                self.a12_low_since = Some(ppu_dot);
+            }
+        } else if let Some(low_since) = self.a12_low_since.take() {
+            if ppu_dot - low_since >= A12_LOW_FILTER_DOTS {
+                self.clock_irq_counter();
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::emulator::{Result, Error, RomState, NametableMirrorMode};
+
+mod nrom;
+mod mmc1;
+mod uxrom;
+mod cnrom;
+mod mmc3;
+mod axrom;
+
+/// A cartridge board. The mapper owns everything the cartridge connects to: CPU addresses $4020-$FFFF, PPU addresses
+/// $0000-$1FFF, the choice of nametable mirroring and the cartridge's IRQ output.
+pub trait Mapper {
+    /// Read from CPU address space, $4020-$FFFF.
+    fn read_cpu(&mut self, address: u16) -> Result<u8>;
+
+    /// Write to CPU address space, $4020-$FFFF. `cpu_cycle` is the CPU cycle count at the time of the write. Read-modify-write
+    /// instructions write twice on consecutive cycles, first the unmodified value and then the result, so the second
+    /// write's `cpu_cycle` is one more than the first's.
+    fn write_cpu(&mut self, address: u16, value: u8, cpu_cycle: u64) -> Result<()>;
+
+    /// Read from PPU address space, $0000-$1FFF. This takes `&self` as it is called from the renderer.
+    fn read_ppu(&self, address: u16) -> Result<u8>;
+
+    /// Write to PPU address space, $0000-$1FFF.
+    fn write_ppu(&mut self, address: u16, value: u8) -> Result<()>;
+
+    /// How the PPU's internal nametable RAM is currently mapped into $2000-$2FFF.
+    fn nametable_mirror_mode(&self) -> NametableMirrorMode;
+
+    /// Extra nametable RAM on the cartridge, which backs pages 2 and 3 of `NametableMirrorMode::FourScreen`. Empty on
+    /// boards without it.
+    fn nametable_ram(&self) -> &[u8] { &[] }
+
+    fn nametable_ram_mut(&mut self) -> &mut [u8] { &mut [] }
+
+    /// Cartridge work RAM, normally at $6000-$7FFF. This is what gets saved on boards with a battery.
+    fn prg_ram(&self) -> &[u8];
+
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    /// Whether the cartridge is currently asserting the CPU's IRQ line.
+    fn irq(&self) -> bool { false }
+
+    /// Called at dot 260 of each visible and pre-render scanline while rendering is enabled, which is when the PPU
+    /// starts fetching sprite patterns for the next line.
+    fn on_scanline(&mut self, _ppu_y: i32) {}
+
+    /// Called once per PPU dot, before the dot is processed. Boards with IRQ counters clocked by M2, such as the FME-7
+    /// and the VRCs, can count CPU cycles as every third dot.
+    fn on_ppu_cycle(&mut self, _ppu_x: i32, _ppu_y: i32) {}
+
+    /// Called with every address the PPU reads or writes, whether for rendering or through $2007, along with the number
+    /// of PPU dots since power on. Boards like the MMC3 watch these to follow the PPU's progress through a frame.
+    fn on_ppu_address(&mut self, _address: u16, _ppu_dot: u64) {}
+}
+
+pub fn create_mapper(rom_state: RomState) -> Result<Box<dyn Mapper>> {
+    // For the discrete logic boards, NES 2.0 submapper 1 means no bus conflicts and 2 means bus conflicts
+    let submapper = rom_state.header.submapper;
+    let bus_conflicts = |default| match submapper {
+        1 => false,
+        2 => true,
+        _ => default
+    };
+
+    // PRG ROM and CHR must be whole numbers of the board's banks. The MMC3 switches 8 KB PRG banks but always fixes the
+    // last two, so needs at least 16 KB.
+    match rom_state.header.mapper_number {
+        0 => new_board(rom_state, 0x4000, 0x2000, nrom::Nrom::new),
+        1 => new_board(rom_state, 0x4000, 0x1000, mmc1::Mmc1::new),
+        2 => new_board(rom_state, 0x4000, 0x2000, |rom_state| uxrom::Uxrom::new(rom_state, bus_conflicts(true))),
+        3 => new_board(rom_state, 0x4000, 0x2000, |rom_state| cnrom::Cnrom::new(rom_state, bus_conflicts(true))),
+        4 => new_board(rom_state, 0x4000, 0x0400, mmc3::Mmc3::new),
+        7 => new_board(rom_state, 0x8000, 0x2000, |rom_state| axrom::Axrom::new(rom_state, bus_conflicts(false))),
+        _ => Err(Error::RomFileError(format!("mapper {} is not supported", rom_state.header.mapper_number)))
+    }
+}
+
+fn new_board<M: Mapper + 'static>(rom_state: RomState, prg_bank_size: usize, chr_bank_size: usize,
+                                  new: impl FnOnce(RomState) -> M) -> Result<Box<dyn Mapper>> {
+    check_rom_size("PRG ROM", rom_state.prg_rom.len(), prg_bank_size)?;
+    check_rom_size("CHR", rom_state.chr.len(), chr_bank_size)?;
+    return Ok(Box::new(new(rom_state)));
+}
+
+// Four-screen boards carry 2 KB of their own RAM for the two nametables the PPU doesn't have
+// https://wiki.nesdev.com/w/index.php/Mirroring#4-Screen
+fn make_nametable_ram(rom_state: &RomState) -> Vec<u8> {
+    if rom_state.header.nametable_mirror_mode == NametableMirrorMode::FourScreen {
+        return vec![0; 0x800];
+    }
+    return Vec::new();
+}
+
+fn check_rom_size(name: &str, size: usize, bank_size: usize) -> Result<()> {
+    if size == 0 || !size.is_multiple_of(bank_size) {
+        return Err(Error::RomFileError(format!("{} size {} is not a multiple of {} bytes", name, size, bank_size)));
+    }
+    return Ok(());
+}
+
+/// Read from cartridge work RAM at $6000-$7FFF, which is mirrored if it is smaller than 8 KB. Without any RAM this reads
+/// as open bus.
+fn read_prg_ram(prg_ram: &[u8], address: u16) -> u8 {
+    if prg_ram.is_empty() {
+        return 0;
+    }
+    return prg_ram[(address - 0x6000) as usize % prg_ram.len()];
+}
+
+fn write_prg_ram(prg_ram: &mut [u8], address: u16, value: u8) {
+    if !prg_ram.is_empty() {
+        let len = prg_ram.len();
+        prg_ram[(address - 0x6000) as usize % len] = value;
+    }
+}
+
+/// On discrete logic boards with bus conflicts, the PRG ROM keeps driving the data bus while the CPU writes to it, so the
+/// value the board sees is the written value ANDed with the ROM byte at that address.
+fn apply_bus_conflict(bus_conflicts: bool, value: u8, rom_byte: u8) -> u8 {
+    if bus_conflicts { value & rom_byte } else { value }
+}
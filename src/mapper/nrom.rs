@@ -0,0 +1,83 @@
+use crate::emulator::{Result, Error, RomState, NametableMirrorMode};
+use crate::mapper::{self, Mapper};
+
+// https://wiki.nesdev.com/w/index.php/NROM
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    nametable_mirror_mode: NametableMirrorMode,
+    nametable_ram: Vec<u8>
+}
+
+impl Nrom {
+    pub fn new(rom_state: RomState) -> Nrom {
+        return Nrom {
+            nametable_ram: mapper::make_nametable_ram(&rom_state),
+            prg_rom: rom_state.prg_rom,
+            prg_ram: rom_state.prg_ram,
+            chr: rom_state.chr,
+            chr_is_ram: rom_state.chr_is_ram,
+            nametable_mirror_mode: rom_state.header.nametable_mirror_mode
+        };
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_cpu(&mut self, address: u16) -> Result<u8> {
+        match address {
+            0x6000 ..= 0x7FFF => Ok(mapper::read_prg_ram(&self.prg_ram, address)),
+
+            // 16 KB PRG ROMs are mirrored into both halves
+            0x8000 ..= 0xFFFF => {
+                let index = (address - 0x8000) as usize;
+                Ok(self.prg_rom[index % self.prg_rom.len()])
+            }
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn write_cpu(&mut self, address: u16, value: u8, _cpu_cycle: u64) -> Result<()> {
+        match address {
+            0x6000 ..= 0x7FFF => {
+                mapper::write_prg_ram(&mut self.prg_ram, address, value);
+                Ok(())
+            }
+
+            _ => Err(Error::AddressError("Invalid memory address".to_string()))
+        }
+    }
+
+    fn read_ppu(&self, address: u16) -> Result<u8> {
+        Ok(self.chr[address as usize])
+    }
+
+    fn write_ppu(&mut self, address: u16, value: u8) -> Result<()> {
+        if self.chr_is_ram {
+            self.chr[address as usize] = value;
+        }
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn nametable_mirror_mode(&self) -> NametableMirrorMode {
+        self.nametable_mirror_mode
+    }
+
+    fn nametable_ram(&self) -> &[u8] {
+        &self.nametable_ram
+    }
+
+    fn nametable_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.nametable_ram
+    }
+}
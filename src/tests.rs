@@ -2,7 +2,10 @@ use std::path::Path;
 use std::fs::File;
 use std::io::{self, BufRead};
 
+use crate::apu::{self, Apu};
+use crate::audio;
 use crate::emulator;
+use crate::mapper::{self, Mapper};
 
 #[derive(Debug)]
 struct LogLine {
@@ -66,3 +69,1016 @@ fn run_test_rom() {
     }
 }
 
+
+/// Build an NROM image with a single 16 KB PRG bank, placing `program` at $8000 and pointing the reset vector at it.
+fn make_nrom_image(program: &[u8]) -> Vec<u8> {
+    let mut result = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg_rom = vec![0u8; 16384];
+    prg_rom[.. program.len()].copy_from_slice(program);
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+    result.extend_from_slice(&prg_rom);
+    result.extend_from_slice(&[0u8; 8192]);
+    return result;
+}
+
+#[test]
+fn power_on_and_reset() {
+    let program = [
+        0xA5, 0x10, // LDA $10
+        0x69, 0x01, // ADC #$01
+        0x85, 0x10, // STA $10
+    ];
+    let rom_state = emulator::RomState::from_bytes(&make_nrom_image(&program)).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+
+    assert_eq!(emu_state.program_counter, 0x8000);
+    assert_eq!(emu_state.stack_pointer, 0xFD);
+    assert_eq!(emu_state.get_flags_as_u8(), 0x24);
+
+    for _ in 0..3 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.reg_a, 1);
+
+    // RAM survives a reset, so the second run continues counting from where the first left off
+    emu_state.reset().unwrap();
+    assert_eq!(emu_state.program_counter, 0x8000);
+    assert_eq!(emu_state.stack_pointer, 0xFA);
+
+    for _ in 0..3 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.reg_a, 2);
+}
+
+/// Build an iNES image for the given mapper, with each 16 KB PRG bank filled with its bank number and each 1 KB of CHR
+/// ROM filled with its 1 KB bank number.
+fn make_rom_image(mapper_number: u8, prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+    let mut result = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, mapper_number << 4, mapper_number & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for bank in 0 .. prg_banks as usize {
+        result.extend_from_slice(&[bank as u8; 16384]);
+    }
+    for bank in 0 .. chr_banks as usize * 8 {
+        result.extend_from_slice(&[bank as u8; 1024]);
+    }
+    return result;
+}
+
+fn write_mmc1_register(mapper: &mut dyn Mapper, address: u16, value: u8, cpu_cycle: &mut u64) {
+    for bit in 0..5 {
+        mapper.write_cpu(address, (value >> bit) & 1, *cpu_cycle).unwrap();
+        *cpu_cycle += 4;
+    }
+}
+
+#[test]
+fn mmc1_banking() {
+    let rom_state = emulator::RomState::from_bytes(&make_rom_image(1, 8, 2)).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+    let mut cpu_cycle = 0;
+
+    // Power on in PRG mode 3, with the last bank fixed at $C000
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 0);
+    assert_eq!(mapper.read_cpu(0xC000).unwrap(), 7);
+
+    write_mmc1_register(mapper.as_mut(), 0xE000, 3, &mut cpu_cycle);
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 3);
+    assert_eq!(mapper.read_cpu(0xFFFF).unwrap(), 7);
+
+    // 4 KB CHR mode
+    write_mmc1_register(mapper.as_mut(), 0x8000, 0x1C, &mut cpu_cycle);
+    write_mmc1_register(mapper.as_mut(), 0xA000, 2, &mut cpu_cycle);
+    write_mmc1_register(mapper.as_mut(), 0xC000, 1, &mut cpu_cycle);
+    assert_eq!(mapper.read_ppu(0x0000).unwrap(), 8);
+    assert_eq!(mapper.read_ppu(0x1000).unwrap(), 4);
+
+    // The second of two writes on consecutive cycles is ignored
+    mapper.write_cpu(0xE000, 1, cpu_cycle).unwrap();
+    mapper.write_cpu(0xE000, 0, cpu_cycle).unwrap();
+    cpu_cycle += 4;
+    for _ in 0..4 {
+        mapper.write_cpu(0xE000, 0, cpu_cycle).unwrap();
+        cpu_cycle += 4;
+    }
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 1);
+}
+
+#[test]
+fn uxrom_banking_and_chr_ram() {
+    let rom_state = emulator::RomState::from_bytes(&make_rom_image(2, 8, 0)).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+
+    // Write over the last bank, which is filled with 7, to avoid a bus conflict
+    mapper.write_cpu(0xC000, 5, 0).unwrap();
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 5);
+    assert_eq!(mapper.read_cpu(0xC000).unwrap(), 7);
+
+    mapper.write_ppu(0x1234, 0xAB).unwrap();
+    assert_eq!(mapper.read_ppu(0x1234).unwrap(), 0xAB);
+}
+
+#[test]
+fn cnrom_bus_conflicts() {
+    let mut image = make_rom_image(3, 2, 4);
+    image[16 + 0x1000] = 0x02;
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+
+    // The written value is ANDed with the ROM byte at the same address
+    mapper.write_cpu(0x9000, 0x03, 0).unwrap();
+    assert_eq!(mapper.read_ppu(0x0000).unwrap(), 2 * 8);
+
+    mapper.write_cpu(0x8000, 0x03, 4).unwrap();
+    assert_eq!(mapper.read_ppu(0x0400).unwrap(), 1);
+
+    // With no CHR ROM, the board has 8 KB of CHR RAM instead
+    let rom_state = emulator::RomState::from_bytes(&make_rom_image(3, 2, 0)).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+    mapper.write_ppu(0x1234, 0xAB).unwrap();
+    assert_eq!(mapper.read_ppu(0x1234).unwrap(), 0xAB);
+}
+
+#[test]
+fn mmc3_banking_and_irq() {
+    let rom_state = emulator::RomState::from_bytes(&make_rom_image(4, 4, 2)).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+
+    // R6 selects the 8 KB bank at $8000, and the last two 8 KB banks are fixed
+    mapper.write_cpu(0x8000, 6, 0).unwrap();
+    mapper.write_cpu(0x8001, 2, 0).unwrap();
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 1);
+    assert_eq!(mapper.read_cpu(0xC000).unwrap(), 3);
+
+    // R2 selects the 1 KB bank at $1000, or at $0000 with A12 inversion
+    mapper.write_cpu(0x8000, 2, 0).unwrap();
+    mapper.write_cpu(0x8001, 9, 0).unwrap();
+    assert_eq!(mapper.read_ppu(0x1000).unwrap(), 9);
+    mapper.write_cpu(0x8000, 0x82, 0).unwrap();
+    assert_eq!(mapper.read_ppu(0x0000).unwrap(), 9);
+
+    // IRQ fires when the counter reaches zero, and is acknowledged by disabling it. The counter is clocked by A12 rising
+    // after being low for long enough, once per scanline.
+    mapper.write_cpu(0xC000, 2, 0).unwrap();
+    mapper.write_cpu(0xC001, 0, 0).unwrap();
+    mapper.write_cpu(0xE001, 0, 0).unwrap();
+    let mut ppu_dot = 0;
+    let mut scanline = |mapper: &mut Box<dyn Mapper>| {
+        mapper.on_ppu_address(0x0000, ppu_dot);
+        mapper.on_ppu_address(0x1000, ppu_dot + 4); // too soon to count
+        mapper.on_ppu_address(0x0000, ppu_dot + 6);
+        mapper.on_ppu_address(0x1000, ppu_dot + 260);
+        ppu_dot += 341;
+    };
+    scanline(&mut mapper);
+    scanline(&mut mapper);
+    assert!(!mapper.irq());
+    scanline(&mut mapper);
+    assert!(mapper.irq());
+    mapper.write_cpu(0xE000, 0, 0).unwrap();
+    assert!(!mapper.irq());
+}
+
+// Run a program on MMC3 that enables rendering with the given PPUCTRL and a scanline IRQ after 20 lines, returning
+// the PPU position when the IRQ handler is reached within a few frames
+fn run_mmc3_irq_test(ppu_ctrl: u8) -> Option<(i32, i32)> {
+    let program = [
+        0xA9, 0x40, 0x8D, 0x17, 0x40, // LDA #$40; STA $4017    no APU frame IRQ
+        0x2C, 0x02, 0x20,             // BIT $2002
+        0x10, 0xFB,                   // BPL $E005              wait for VBlank
+        0xA9, 0x14, 0x8D, 0x00, 0xC0, // LDA #$14; STA $C000    IRQ latch
+        0x8D, 0x01, 0xC0,             // STA $C001              reload
+        0x8D, 0x01, 0xE0,             // STA $E001              enable IRQ
+        0xA9, ppu_ctrl,               // LDA #ppu_ctrl
+        0x8D, 0x00, 0x20,             // STA $2000
+        0xA9, 0x18, 0x8D, 0x01, 0x20, // LDA #$18; STA $2001    show background and sprites
+        0x58,                         // CLI
+        0x4C, 0x20, 0xE0,             // JMP *
+    ];
+    let mut image = make_rom_image(4, 2, 1);
+    image[16 + 0x6000 .. 16 + 0x6000 + program.len()].copy_from_slice(&program);
+    image[16 + 0x6100 .. 16 + 0x6103].copy_from_slice(&[0x4C, 0x00, 0xE1]); // $E100: JMP *
+    image[16 + 0x7FFC .. 16 + 0x8000].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE1]);
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    for _ in 0..100000 {
+        if emu_state.program_counter == 0xE100 {
+            return Some((emu_state.ppu_y, emu_state.ppu_x));
+        }
+        emu_state.run_one_instruction().unwrap();
+    }
+    return None;
+}
+
+#[test]
+fn mmc3_irq_timing() {
+    // The counter is loaded on the pre-render line, and reaches zero 20 lines later. With the background at $0000 and
+    // sprites at $1000, A12 rises when sprite fetches start at dot 261...
+    let (ppu_y, ppu_x) = run_mmc3_irq_test(0x08).unwrap();
+    let position = ppu_y * 341 + ppu_x;
+    assert!((19 * 341 + 261 .. 19 * 341 + 261 + 40).contains(&position), "{} {}", ppu_y, ppu_x);
+
+    // ... and the other way round, it rises when background fetches for the next line start at dot 325
+    let (ppu_y, ppu_x) = run_mmc3_irq_test(0x10).unwrap();
+    let position = ppu_y * 341 + ppu_x;
+    assert!((19 * 341 + 325 .. 19 * 341 + 325 + 40).contains(&position), "{} {}", ppu_y, ppu_x);
+
+    // With both at $0000, A12 never rises
+    assert_eq!(run_mmc3_irq_test(0x00), None);
+}
+
+#[test]
+fn axrom_banking_and_single_screen() {
+    let rom_state = emulator::RomState::from_bytes(&make_rom_image(7, 8, 0)).unwrap();
+    let mut mapper = mapper::create_mapper(rom_state).unwrap();
+    assert_eq!(mapper.nametable_mirror_mode(), emulator::NametableMirrorMode::SingleScreenLower);
+
+    mapper.write_cpu(0x8000, 0x12, 0).unwrap();
+    assert_eq!(mapper.read_cpu(0x8000).unwrap(), 4);
+    assert_eq!(mapper.read_cpu(0xC000).unwrap(), 5);
+    assert_eq!(mapper.nametable_mirror_mode(), emulator::NametableMirrorMode::SingleScreenUpper);
+}
+
+#[test]
+fn nes2_header() {
+    let mut image = make_rom_image(0, 2, 0);
+    image[6] = 0x41;        // mapper low nibble 4, vertical mirroring
+    image[7] = 0x18;        // NES 2.0, mapper middle nibble 1
+    image[8] = 0x21;        // submapper 2, mapper high nibble 1
+    image[10] = 0x07;       // 8 KB PRG RAM
+    image[11] = 0x90;       // 32 KB CHR NVRAM
+    image[12] = 0x01;       // PAL
+    image[15] = 0x01;       // standard controllers
+    let header = emulator::RomHeader::parse(&image).unwrap();
+
+    assert!(header.is_nes2);
+    assert_eq!(header.mapper_number, 0x114);
+    assert_eq!(header.submapper, 2);
+    assert_eq!(header.prg_rom_size, 32768);
+    assert_eq!(header.chr_rom_size, 0);
+    assert_eq!(header.prg_ram_size, 8192);
+    assert_eq!(header.chr_nvram_size, 32768);
+    assert_eq!(header.nametable_mirror_mode, emulator::NametableMirrorMode::Horizontal);
+    assert_eq!(header.timing_mode, emulator::TimingMode::Pal);
+    assert_eq!(header.console_type, emulator::ConsoleType::Nes);
+    assert_eq!(header.default_expansion_device, 1);
+
+    // Exponent-multiplier ROM size: 2^10 * 3
+    image[4] = (10 << 2) | 1;
+    image[9] = 0x0F;
+    assert_eq!(emulator::RomHeader::parse(&image).unwrap().prg_rom_size, 3072);
+
+    // ... which mappers can't use unless it is a whole number of banks
+    image[6] = 0x20;
+    image[7] = 0x08;
+    image[8] = 0x00;
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    assert!(matches!(mapper::create_mapper(rom_state), Err(emulator::Error::RomFileError(_))));
+
+    // 2^63 * 7 doesn't fit
+    image[4] = (63 << 2) | 3;
+    assert!(matches!(emulator::RomHeader::parse(&image), Err(emulator::Error::RomFileError(_))));
+
+    // An iNES header with "DiskDude!" over bytes 7-15 only has bytes 4-6 to go on
+    let mut image = make_rom_image(0, 2, 1);
+    image[6] = 0x12;        // mapper low nibble 1, battery
+    image[7 .. 16].copy_from_slice(b"DiskDude!");
+    let header = emulator::RomHeader::parse(&image).unwrap();
+
+    assert!(!header.is_nes2);
+    assert_eq!(header.mapper_number, 1);
+    assert_eq!(header.prg_ram_size, 0);
+    assert_eq!(header.prg_nvram_size, 8192);
+    assert_eq!(header.timing_mode, emulator::TimingMode::Ntsc);
+    assert_eq!(header.console_type, emulator::ConsoleType::Nes);
+}
+
+#[test]
+fn battery_ram_persistence() {
+    let program = [
+        0xA9, 0x5A,       // LDA #$5A
+        0x8D, 0x34, 0x62, // STA $6234
+        0xAD, 0x34, 0x62, // LDA $6234
+    ];
+    let mut image = make_nrom_image(&program);
+    image[6] |= 0x02; // battery
+
+    let dir = std::env::temp_dir().join("emulator_rs_battery_ram_persistence");
+    std::fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("test.nes");
+    let save_path = dir.join("test.sav");
+    std::fs::write(&rom_path, &image).unwrap();
+    let _ = std::fs::remove_file(&save_path);
+
+    let mut emu_state = emulator::EmuState::new(&rom_path).unwrap();
+    emu_state.run_one_instruction().unwrap();
+    emu_state.run_one_instruction().unwrap();
+    emu_state.flush_battery_ram().unwrap();
+    assert_eq!(std::fs::read(&save_path).unwrap()[0x234], 0x5A);
+
+    // The saved RAM is loaded back in when the ROM is next opened
+    let mut emu_state = emulator::EmuState::new(&rom_path).unwrap();
+    emu_state.program_counter = 0x8005;
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.reg_a, 0x5A);
+}
+
+#[test]
+fn trainer() {
+    let program = [
+        0xAD, 0x00, 0x70, // LDA $7000
+        0xAE, 0xFF, 0x71, // LDX $71FF
+    ];
+    let nrom_image = make_nrom_image(&program);
+    let mut image = nrom_image[.. 16].to_vec();
+    image[6] |= 0x04;
+    let mut trainer = [0u8; 512];
+    trainer[0] = 0x12;
+    trainer[511] = 0x34;
+    image.extend_from_slice(&trainer);
+    image.extend_from_slice(&nrom_image[16 ..]);
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    assert_eq!(emu_state.program_counter, 0x8000);
+    emu_state.run_one_instruction().unwrap();
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.reg_a, 0x12);
+    assert_eq!(emu_state.reg_x, 0x34);
+}
+
+#[test]
+fn four_screen_nametables() {
+    let program = [
+        0xA9, 0x2C, 0x8D, 0x06, 0x20, // LDA #$2C, STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00, STA $2006
+        0xA9, 0x44, 0x8D, 0x07, 0x20, // LDA #$44, STA $2007
+        0xA9, 0x28, 0x8D, 0x06, 0x20, // LDA #$28, STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00, STA $2006
+        0xAD, 0x07, 0x20,             // LDA $2007, to fill the read buffer
+        0xAD, 0x07, 0x20,             // LDA $2007
+    ];
+    let mut image = make_nrom_image(&program);
+    image[6] |= 0x08;
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    assert_eq!(rom_state.header().nametable_mirror_mode, emulator::NametableMirrorMode::FourScreen);
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    while emu_state.program_counter < 0x8000 + program.len() as u16 {
+        emu_state.run_one_instruction().unwrap();
+    }
+
+    // With the cartridge's extra RAM, $2C00 does not mirror $2800
+    assert_eq!(emu_state.reg_a, 0x00);
+}
+
+#[test]
+fn apu_pulse_channel() {
+    let mut apu = Apu::new();
+    apu.write_register(0x4015, 0x01);
+    apu.write_register(0x4000, 0x9F); // 50% duty, constant volume 15
+    apu.write_register(0x4002, 0x20);
+    apu.write_register(0x4003, 0x00); // length counter 10
+    assert_eq!(apu.read_status(), 0x01);
+
+    for _ in 0..1000 { apu.step(); }
+    // The idle triangle channel contributes a constant offset
+    let samples = apu.take_samples();
+    let max_sample = samples.iter().cloned().fold(0.0, f32::max);
+    let min_sample = samples.iter().cloned().fold(1.0, f32::min);
+    assert!((max_sample - min_sample - 95.88 / (8128.0 / 15.0 + 100.0)).abs() < 1e-6);
+
+    // A sweep that would overflow the period mutes the channel, even when the sweep unit is disabled
+    apu.write_register(0x4001, 0x01);
+    apu.write_register(0x4002, 0xF0);
+    apu.write_register(0x4003, 0x07);
+    for _ in 0..1000 { apu.step(); }
+    let samples = apu.take_samples();
+    assert!(samples.iter().all(|&s| s == samples[0]));
+
+    // The length counter runs out after 10 half frames
+    for _ in 0..5 * 29830 { apu.step(); }
+    assert_eq!(apu.read_status() & 0x01, 0x00);
+}
+
+#[test]
+fn apu_triangle_and_noise_channels() {
+    let mut apu = Apu::new();
+    apu.write_register(0x4015, 0x0C);
+    apu.write_register(0x4008, 0x81); // control flag set, linear counter 1
+    apu.write_register(0x400A, 0x10);
+    apu.write_register(0x400B, 0x00);
+    apu.write_register(0x400C, 0x30); // constant volume 0, length counter halted
+    apu.write_register(0x400E, 0x00);
+    apu.write_register(0x400F, 0x00);
+    assert_eq!(apu.read_status(), 0x0C);
+
+    // The triangle doesn't play until the first quarter frame loads the linear counter
+    for _ in 0..7000 { apu.step(); }
+    let samples = apu.take_samples();
+    assert!(samples.iter().all(|&s| s == samples[0]));
+
+    apu.write_register(0x400C, 0x3F); // constant volume 15
+    for _ in 0..2000 { apu.step(); }
+    let samples = apu.take_samples();
+    let max_sample = samples.iter().cloned().fold(0.0, f32::max);
+    let min_sample = samples.iter().cloned().fold(1.0, f32::min);
+    assert_eq!(max_sample, 159.79 / (1.0 / (15.0 / 8227.0 + 15.0 / 12241.0) + 100.0));
+    assert_eq!(min_sample, 0.0);
+
+    // Disabling the channels clears their length counters
+    apu.write_register(0x4015, 0x00);
+    assert_eq!(apu.read_status(), 0x00);
+}
+
+#[test]
+fn apu_dmc_dma_and_irq() {
+    let program = [
+        0x58,             // CLI
+        0xA9, 0x8F,       // LDA #$8F     IRQ enabled, fastest rate
+        0x8D, 0x10, 0x40, // STA $4010
+        0xA9, 0x00,       // LDA #$00
+        0x8D, 0x12, 0x40, // STA $4012    sample at $C000
+        0x8D, 0x13, 0x40, // STA $4013    one byte long
+        0xA9, 0x10,       // LDA #$10
+        0x8D, 0x15, 0x40, // STA $4015    start the sample
+        0x4C, 0x13, 0x80, // JMP $8013
+    ];
+    let mut image = make_nrom_image(&program);
+
+    // IRQ handler at $9000
+    image[16 + 0x1000 .. 16 + 0x1006].copy_from_slice(&[
+        0xAD, 0x15, 0x40, // LDA $4015
+        0x4C, 0x03, 0x90, // JMP $9003
+    ]);
+    image[16 + 0x3FFE] = 0x00;
+    image[16 + 0x3FFF] = 0x90;
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    for _ in 0..20 { emu_state.run_one_instruction().unwrap(); }
+
+    // The single byte has been fetched, so the sample has ended and raised an IRQ
+    assert_eq!(emu_state.program_counter, 0x9003);
+    assert_eq!(emu_state.reg_a & 0x90, 0x80);
+}
+
+// Sets up a looping DMC sample at the fastest rate, then runs `loop_program` at $8020, which should keep reading a
+// register until the read sees a DMC DMA's extra access and drops through to a JMP * at `end_address`. The loop's length
+// doesn't divide the DMA period, so every alignment of DMA and read comes up. Returns None if the end is never reached.
+fn run_dmc_conflict_test(loop_program: &[u8], end_address: u16, dmc_enabled: bool) -> Option<emulator::EmuState> {
+    let setup = [
+        0xA9, 0x4F,       // LDA #$4F     looping, fastest rate
+        0x8D, 0x10, 0x40, // STA $4010
+        0xA9, 0x00,       // LDA #$00
+        0x8D, 0x12, 0x40, // STA $4012    sample at $C000
+        0xA9, 0xFF,       // LDA #$FF
+        0x8D, 0x13, 0x40, // STA $4013    longest sample
+        0xA9, if dmc_enabled { 0x10 } else { 0x00 },
+        0x8D, 0x15, 0x40, // STA $4015
+        0x4C, 0x20, 0x80, // JMP $8020
+    ];
+    let mut program = vec![0xEA; 0x20];
+    program[.. setup.len()].copy_from_slice(&setup);
+    program.extend_from_slice(loop_program);
+
+    let rom_state = emulator::RomState::from_bytes(&make_nrom_image(&program)).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    emu_state.set_joypad1_buttons([true, false, true, false, false, false, false, false]);
+    while emu_state.cycle_count() < 100_000 {
+        emu_state.run_one_instruction().unwrap();
+        if emu_state.program_counter == end_address {
+            return Some(emu_state);
+        }
+    }
+    return None;
+}
+
+#[test]
+fn dmc_dma_read_conflicts() {
+    let joypad_loop = [
+        0xA9, 0x01,       // LDA #$01
+        0x8D, 0x16, 0x40, // STA $4016    strobe
+        0x4A,             // LSR A
+        0x8D, 0x16, 0x40, // STA $4016
+        0xAD, 0x16, 0x40, // LDA $4016    button A, which is pressed
+        0xD0, 0xF2,       // BNE $8020
+        0xAD, 0x16, 0x40, // LDA $4016
+        0x4C, 0x31, 0x80, // JMP $8031
+    ];
+
+    // The DMA's extra read of $4016 skips button A, so the first read sees B and the second Select
+    let emu_state = run_dmc_conflict_test(&joypad_loop, 0x8031, true).expect("DMA never conflicted with the joypad read");
+    assert_eq!(emu_state.reg_a, 1);
+    assert!(run_dmc_conflict_test(&joypad_loop, 0x8031, false).is_none());
+
+    let ppu_data_loop = [
+        0xA9, 0x20,       // LDA #$20
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00,       // LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0x8D, 0x07, 0x20, // STA $2007    $2000 = 0
+        0xA9, 0x01,       // LDA #$01
+        0x8D, 0x07, 0x20, // STA $2007    $2001 = 1
+        0xA9, 0x02,       // LDA #$02
+        0x8D, 0x07, 0x20, // STA $2007    $2002 = 2
+        0xA9, 0x20,       // LDA #$20
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00,       // LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0xAD, 0x07, 0x20, // LDA $2007    fill the read buffer
+        0xAD, 0x07, 0x20, // LDA $2007    $2000
+        0xF0, 0xEE,       // BEQ $8037
+        0xAA,             // TAX
+        0xAD, 0x07, 0x20, // LDA $2007
+        0x4C, 0x4D, 0x80, // JMP $804D
+    ];
+
+    // The DMA's extra read of $2007 increments the address once more, so the second read sees $2001 and the next $2002
+    let emu_state = run_dmc_conflict_test(&ppu_data_loop, 0x804D, true).expect("DMA never conflicted with the PPUDATA read");
+    assert_eq!(emu_state.reg_x, 1);
+    assert_eq!(emu_state.reg_a, 2);
+    assert!(run_dmc_conflict_test(&ppu_data_loop, 0x804D, false).is_none());
+}
+
+#[test]
+fn apu_frame_counter() {
+    let mut apu = Apu::new();
+
+    // 4-step mode raises the frame IRQ at the end of each sequence, and reading $4015 acknowledges it
+    for _ in 0..29827 { apu.step(); }
+    assert!(!apu.irq());
+    apu.step();
+    assert!(apu.irq());
+    assert_eq!(apu.read_status(), 0x40);
+    assert_eq!(apu.read_status(), 0x00);
+
+    // Setting the inhibit flag clears the IRQ and stops it being raised again
+    for _ in 0..29830 { apu.step(); }
+    assert!(apu.irq());
+    apu.write_register(0x4017, 0x40);
+    assert!(!apu.irq());
+    for _ in 0..2 * 29830 { apu.step(); }
+    assert!(!apu.irq());
+
+    // 5-step mode never raises the IRQ, and clocks the length counters a few cycles after the write takes effect
+    apu.write_register(0x4015, 0x01);
+    apu.write_register(0x4003, 0x18); // length counter 2
+    apu.write_register(0x4017, 0x80);
+    for _ in 0..14900 { apu.step(); }
+    assert_eq!(apu.read_status(), 0x01);
+    for _ in 0..20 { apu.step(); }
+    assert_eq!(apu.read_status(), 0x00);
+    for _ in 0..2 * 37282 { apu.step(); }
+    assert!(!apu.irq());
+}
+
+#[test]
+fn apu_sample_buffer() {
+    let mut apu = Apu::new();
+    for _ in 0..100 { apu.step(); }
+    assert_eq!(apu.take_samples().len(), 100);
+    assert!(apu.take_samples().is_empty());
+
+    // Samples that are never taken don't build up without limit
+    for _ in 0 .. apu::MAX_BUFFERED_SAMPLES * 2 { apu.step(); }
+    assert!(apu.take_samples().len() <= apu::MAX_BUFFERED_SAMPLES);
+}
+
+#[test]
+fn audio_resampler() {
+    let mut resampler = audio::Resampler::new(emulator::CPU_CLOCK_RATE, 44100.0);
+
+    // One second of input gives one second of output, even when split across calls
+    let mut output = Vec::new();
+    for _ in 0..60 {
+        resampler.process(&vec![0.5; emulator::CPU_CLOCK_RATE as usize / 60], &mut output);
+    }
+    assert!((output.len() as i64 - 44100).abs() <= 1);
+
+    // A constant input decays to zero
+    assert!(output[0] > 0.4);
+    assert!(output.last().unwrap().abs() < 0.001);
+}
+
+#[test]
+fn wav_export() {
+    let program = [
+        0xA9, 0x0F,       // LDA #$0F
+        0x8D, 0x15, 0x40, // STA $4015
+        0xA9, 0xBF,       // LDA #$BF     50% duty, constant volume 15
+        0x8D, 0x00, 0x40, // STA $4000
+        0x8D, 0x02, 0x40, // STA $4002
+        0x8D, 0x03, 0x40, // STA $4003
+        0xD0, 0xFE,       // BNE *        NMI stays disabled
+    ];
+    let rom_state = emulator::RomState::from_bytes(&make_nrom_image(&program)).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+
+    let mut wav_writer = audio::WavWriter::new(io::Cursor::new(Vec::new()), 44100).unwrap();
+    for _ in 0..10 {
+        emu_state.run_to_next_vblank_with_sink(&mut wav_writer).unwrap();
+    }
+    let wav = wav_writer.finish().unwrap().into_inner();
+
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes([wav[4], wav[5], wav[6], wav[7]]) as usize, wav.len() - 8);
+    let data_size = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize;
+    assert_eq!(data_size, wav.len() - 44);
+
+    // About a sixth of a second of audio, which isn't silent
+    assert!((data_size / 2) > 7000 && (data_size / 2) < 8000);
+    assert!(wav[44..].chunks(2).any(|sample| i16::from_le_bytes([sample[0], sample[1]]).abs() > 1000));
+}
+
+#[test]
+fn interrupts() {
+    let program = [
+        0x4C, 0x00, 0x80, // $8000: JMP $8000
+        0x58,             // $8003: CLI
+        0xEA,             // $8004: NOP
+        0x00, 0xEA,       // $8005: BRK
+        0xEA,             // $8007: NOP
+    ];
+    let mut image = make_nrom_image(&program);
+
+    // IRQ/BRK handler at $9000
+    image[16 + 0x1000 .. 16 + 0x1004].copy_from_slice(&[
+        0xAD, 0x15, 0x40, // LDA $4015    acknowledge the frame IRQ
+        0x40,             // RTI
+    ]);
+    image[16 + 0x3FFE] = 0x00;
+    image[16 + 0x3FFF] = 0x90;
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+
+    // Wait for the APU frame IRQ, which is masked by the I flag set on reset
+    for _ in 0..12000 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.program_counter, 0x8000);
+
+    // CLI only takes effect after the following instruction
+    emu_state.program_counter = 0x8003;
+    emu_state.run_one_instruction().unwrap();
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.program_counter, 0x8005);
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.program_counter, 0x9000);
+    assert_eq!(emu_state.stack_pointer, 0xFA);
+    assert_eq!(emu_state.get_flags_as_u8() & 0x04, 0x04);
+
+    for _ in 0..2 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.program_counter, 0x8005);
+    assert_eq!(emu_state.get_flags_as_u8() & 0x04, 0x00);
+
+    // BRK skips its padding byte
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.program_counter, 0x9000);
+    for _ in 0..2 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.program_counter, 0x8007);
+}
+
+#[test]
+fn unofficial_opcodes_and_cycle_counts() {
+    let program: [(&[u8], u64); 16] = [
+        (&[0xA9, 0xF0],       2), // LDA #$F0
+        (&[0xA2, 0x3C],       2), // LDX #$3C
+        (&[0x87, 0x10],       3), // SAX $10      $10 = $30
+        (&[0xA7, 0x10],       3), // LAX $10      A = X = $30
+        (&[0xC7, 0x10],       5), // DCP $10      $10 = $2F, carry set as A > $2F
+        (&[0xE7, 0x10],       5), // ISC $10      $10 = $30, A = 0
+        (&[0x07, 0x10],       5), // SLO $10      $10 = $60, A = $60
+        (&[0xE6, 0x10],       5), // INC $10      $10 = $61
+        (&[0x9D, 0x00, 0x02], 5), // STA $0200,X  always takes the extra cycle
+        (&[0xDF, 0x00, 0x02], 7), // DCP $0200,X
+        (&[0x1C, 0x00, 0x02], 4), // NOP $0200,X  no page crossed
+        (&[0x1A],             2), // NOP
+        (&[0x08],             3), // PHP
+        (&[0x28],             4), // PLP
+        (&[0xCB, 0x10],       2), // AXS #$10     X = ($60 & $30) - $10 = $10
+        (&[0x4C, 0x00, 0x90], 3), // JMP $9000
+    ];
+    let bytes: Vec<u8> = program.iter().flat_map(|(instruction, _)| instruction.iter().cloned()).collect();
+
+    let rom_state = emulator::RomState::from_bytes(&make_nrom_image(&bytes)).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+
+    for (instruction, cycles) in program.iter() {
+        let start_cycle = emu_state.cycle_count();
+        emu_state.run_one_instruction().unwrap();
+        assert_eq!(emu_state.cycle_count() - start_cycle, *cycles, "{:02X?}", instruction);
+    }
+
+    assert_eq!(emu_state.reg_a, 0x60);
+    assert_eq!(emu_state.reg_x, 0x10);
+    assert_eq!(emu_state.program_counter, 0x9000);
+
+    // JAM locks up the real CPU, and is reported as an error
+    let rom_state = emulator::RomState::from_bytes(&make_nrom_image(&[0x02])).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    assert!(matches!(emu_state.run_one_instruction(), Err(emulator::Error::InvalidInstructionError(0x02))));
+}
+
+// Run until a whole frame has been rendered after the program's setup code, which starts partway through the first frame
+fn run_to_end_of_full_frame(emu_state: &mut emulator::EmuState) {
+    while emu_state.ppu_y != 240 { emu_state.run_one_instruction().unwrap(); }
+    while emu_state.ppu_y != -1 { emu_state.run_one_instruction().unwrap(); }
+    while emu_state.ppu_y != 240 { emu_state.run_one_instruction().unwrap(); }
+}
+
+#[test]
+fn ppu_scroll_registers() {
+    let program = [
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00; STA $2006
+        0xA9, 0x0F, 0x8D, 0x07, 0x20, // LDA #$0F; STA $2007    backdrop
+        0xA9, 0x30, 0x8D, 0x07, 0x20, // LDA #$30; STA $2007    background colour 1
+        0xA9, 0x24, 0x8D, 0x06, 0x20, // LDA #$24; STA $2006
+        0xA9, 0x21, 0x8D, 0x06, 0x20, // LDA #$21; STA $2006
+        0xA9, 0x01, 0x8D, 0x07, 0x20, // LDA #$01; STA $2007    tile 1 at row 1, column 1 of the second nametable
+        0xAD, 0x02, 0x20,             // LDA $2002              reset the write toggle
+        0xA9, 0x04, 0x8D, 0x05, 0x20, // LDA #$04; STA $2005    X scroll
+        0xA9, 0x08, 0x8D, 0x05, 0x20, // LDA #$08; STA $2005    Y scroll
+        0xA9, 0x01, 0x8D, 0x00, 0x20, // LDA #$01; STA $2000    second nametable
+        0xA9, 0x0A, 0x8D, 0x01, 0x20, // LDA #$0A; STA $2001    show background
+        0x4C, 0x3A, 0x80,             // JMP *
+    ];
+    let mut image = make_nrom_image(&program);
+    image[6] = 0x01; // vertical mirroring, so $2400 is a separate nametable
+    image[16 + 0x4000 + 16 .. 16 + 0x4000 + 24].fill(0xFF); // tile 1 is solid colour 1
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    run_to_end_of_full_frame(&mut emu_state);
+
+    let is_lit = |x: usize, y: usize| {
+        let index = (y * 256 + x) * 4;
+        return emu_state.frame_buffer()[index .. index + 3] != [0, 0, 0]; // the backdrop is black
+    };
+    // The tile at (8, 8) in the nametable appears at (4, 0) on screen
+    let lit: Vec<(usize, usize)> = (0..16).flat_map(|y| (0..16).map(move |x| (x, y))).filter(|&(x, y)| is_lit(x, y)).collect();
+    let expected: Vec<(usize, usize)> = (0..8).flat_map(|y| (4..12).map(move |x| (x, y))).collect();
+    assert_eq!(lit, expected);
+}
+
+#[test]
+fn tall_sprites() {
+    let program = [
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00; STA $2006
+        0xA9, 0x0F, 0x8D, 0x07, 0x20, // LDA #$0F; STA $2007    backdrop
+        0xA9, 0x30, 0x8D, 0x07, 0x20, // LDA #$30; STA $2007    background colour 1
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x11, 0x8D, 0x06, 0x20, // LDA #$11; STA $2006
+        0xA9, 0x16, 0x8D, 0x07, 0x20, // LDA #$16; STA $2007    sprite colour 1
+        0xA9, 0x20, 0x8D, 0x06, 0x20, // LDA #$20; STA $2006
+        0xA9, 0x61, 0x8D, 0x06, 0x20, // LDA #$61; STA $2006
+        0xA9, 0x01, 0x8D, 0x07, 0x20, // LDA #$01; STA $2007    background tile at (8, 24)
+        0xA9, 0xFF,                   // LDA #$FF
+        0xA2, 0x00,                   // LDX #$00
+        0x9D, 0x00, 0x02,             // STA $0200,X            hide all sprites
+        0xE8,                         // INX
+        0xD0, 0xFA,                   // BNE $8036
+        0xA9, 0x0F, 0x8D, 0x00, 0x02, // LDA #$0F; STA $0200    sprite 0 at (8, 16), tiles $02 and $03 in $1000
+        0xA9, 0x03, 0x8D, 0x01, 0x02, // LDA #$03; STA $0201
+        0xA9, 0x00, 0x8D, 0x02, 0x02, // LDA #$00; STA $0202
+        0xA9, 0x08, 0x8D, 0x03, 0x02, // LDA #$08; STA $0203
+        0xA9, 0x0F, 0x8D, 0x04, 0x02, // LDA #$0F; STA $0204    sprite 1 at (100, 16), flipped vertically
+        0xA9, 0x03, 0x8D, 0x05, 0x02, // LDA #$03; STA $0205
+        0xA9, 0x80, 0x8D, 0x06, 0x02, // LDA #$80; STA $0206
+        0xA9, 0x64, 0x8D, 0x07, 0x02, // LDA #$64; STA $0207
+        0xA9, 0x02, 0x8D, 0x14, 0x40, // LDA #$02; STA $4014    OAM DMA
+        0xA9, 0x00, 0x8D, 0x05, 0x20, // LDA #$00; STA $2005
+        0x8D, 0x05, 0x20,             // STA $2005
+        0xA9, 0x20, 0x8D, 0x00, 0x20, // LDA #$20; STA $2000    8x16 sprites
+        0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E; STA $2001    show background and sprites
+        0x2C, 0x02, 0x20,             // BIT $2002
+        0x50, 0xFB,                   // BVC $807B              wait for sprite 0 hit
+        0xA9, 0x42,                   // LDA #$42
+        0x4C, 0x82, 0x80,             // JMP *
+    ];
+    let mut image = make_nrom_image(&program);
+    image[16 + 0x4000 + 0x0010 .. 16 + 0x4000 + 0x0018].fill(0xFF); // background tile 1 is solid
+    image[16 + 0x4000 + 0x1030 .. 16 + 0x4000 + 0x1038].fill(0xFF); // sprite tile $03 is solid, $02 is blank
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    run_to_end_of_full_frame(&mut emu_state);
+
+    // Sprite 0 only overlaps the background with its lower half
+    assert_eq!(emu_state.reg_a, 0x42);
+
+    let pixel = |x: usize, y: usize| {
+        let index = (y * 256 + x) * 4;
+        return emu_state.frame_buffer()[index .. index + 4].to_vec();
+    };
+    let backdrop = pixel(0, 0);
+    let sprite = pixel(8, 24);
+    assert_ne!(sprite, backdrop);
+
+    assert_eq!(pixel(8, 16), backdrop);
+    assert_eq!(pixel(15, 31), sprite);
+    assert_eq!(pixel(100, 16), sprite);
+    assert_eq!(pixel(107, 23), sprite);
+    assert_eq!(pixel(100, 24), backdrop);
+    assert_eq!(pixel(100, 32), backdrop);
+}
+
+// Load a program that shows sprites with the given OAM contents, then sets A to $42 once sprite overflow is set
+fn make_sprite_overflow_test(oam: &[u8; 256], sprite_limit_enabled: bool) -> emulator::EmuState {
+    let program = [
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x11, 0x8D, 0x06, 0x20, // LDA #$11; STA $2006
+        0xA9, 0x30, 0x8D, 0x07, 0x20, // LDA #$30; STA $2007    sprite colour 1
+        0xA9, 0x90, 0x8D, 0x14, 0x40, // LDA #$90; STA $4014    OAM DMA from ROM
+        0xA9, 0x14, 0x8D, 0x01, 0x20, // LDA #$14; STA $2001    show sprites
+        0xAD, 0x02, 0x20,             // LDA $2002
+        0x29, 0x20,                   // AND #$20
+        0xF0, 0xF9,                   // BEQ $8019              wait for sprite overflow
+        0xA9, 0x42,                   // LDA #$42
+        0x4C, 0x22, 0x80,             // JMP *
+    ];
+    let mut image = make_nrom_image(&program);
+    image[16 + 0x1000 .. 16 + 0x1100].copy_from_slice(oam);
+    image[16 + 0x4000 + 0x0010 .. 16 + 0x4000 + 0x0018].fill(0xFF); // tile 1 is solid
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    emu_state.set_sprite_limit_enabled(sprite_limit_enabled);
+    return emu_state;
+}
+
+// Render a frame with the given OAM contents, returning whether the sprite overflow flag was set
+fn run_sprite_overflow_test(oam: &[u8; 256], sprite_limit_enabled: bool) -> (bool, emulator::EmuState) {
+    let mut emu_state = make_sprite_overflow_test(oam, sprite_limit_enabled);
+    run_to_end_of_full_frame(&mut emu_state);
+    return (emu_state.reg_a == 0x42, emu_state);
+}
+
+#[test]
+fn sprite_overflow() {
+    // Sprites side by side on lines 17-24, with everything else below the screen
+    let mut oam = [0xF0u8; 256];
+    for i in 0..9 {
+        oam[i * 4 .. i * 4 + 4].copy_from_slice(&[16, 1, 0, i as u8 * 16]);
+    }
+
+    let (overflow, emu_state) = run_sprite_overflow_test(&oam, true);
+    assert!(overflow);
+    let pixel = |x: usize, y: usize| {
+        let index = (y * 256 + x) * 4;
+        return emu_state.frame_buffer()[index .. index + 4].to_vec();
+    };
+    assert_ne!(pixel(7 * 16, 20), pixel(0, 0));
+    assert_eq!(pixel(8 * 16, 20), pixel(0, 0)); // the ninth sprite isn't drawn
+
+    // The overflow flag is unaffected by the sprite limit
+    let (overflow, emu_state) = run_sprite_overflow_test(&oam, false);
+    assert!(overflow);
+    let index = (20 * 256 + 8 * 16) * 4;
+    assert_ne!(emu_state.frame_buffer()[index .. index + 4], emu_state.frame_buffer()[0 .. 4]);
+
+    // Eight sprites don't overflow
+    oam[8 * 4] = 0xF0;
+    assert!(!run_sprite_overflow_test(&oam, true).0);
+
+    // After eight sprites are found, the hardware checks the tile number of the next sprite as though it were the Y
+    // coordinate, then the attributes of the one after, and so on
+    oam[9 * 4 + 1] = 16;
+    assert!(run_sprite_overflow_test(&oam, true).0);
+
+    // ... so a ninth sprite on the line can be missed
+    oam[9 * 4 .. 9 * 4 + 4].copy_from_slice(&[16, 0xF0, 0xF0, 0xF0]);
+    assert!(!run_sprite_overflow_test(&oam, true).0);
+}
+
+#[test]
+fn oam_address_and_data() {
+    let program = [
+        0xA9, 0x02, 0x8D, 0x03, 0x20, // LDA #$02; STA $2003
+        0xA9, 0xFF, 0x8D, 0x04, 0x20, // LDA #$FF; STA $2004
+        0xA9, 0x02, 0x8D, 0x03, 0x20, // LDA #$02; STA $2003
+        0xAD, 0x04, 0x20,             // LDA $2004              attribute byte
+        0xA9, 0x10, 0x8D, 0x03, 0x20, // LDA #$10; STA $2003
+        0xA9, 0x90, 0x8D, 0x14, 0x40, // LDA #$90; STA $4014    OAM DMA from ROM, starting at OAM byte $10
+        0xAD, 0x04, 0x20,             // LDA $2004
+        0xA9, 0x12, 0x8D, 0x03, 0x20, // LDA #$12; STA $2003
+        0xAD, 0x04, 0x20,             // LDA $2004
+        0xA9, 0x08, 0x8D, 0x01, 0x20, // LDA #$08; STA $2001    show background
+        0x4C, 0x2C, 0x80,             // JMP *
+        0xAD, 0x04, 0x20,             // $802F: LDA $2004
+    ];
+    let mut image = make_nrom_image(&program);
+    for i in 0..256 {
+        image[16 + 0x1000 + i] = 0xFF - i as u8;
+    }
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    let mut run_instructions = |count: usize| {
+        for _ in 0..count { emu_state.run_one_instruction().unwrap(); }
+        return emu_state.reg_a;
+    };
+
+    // Bits 2-4 of attribute bytes read back as 0
+    assert_eq!(run_instructions(7), 0xE3);
+
+    // DMA leaves OAMADDR where it started
+    assert_eq!(run_instructions(5), 0xFF);
+    assert_eq!(run_instructions(3), 0xFD & 0xE3);
+
+    // OAMADDR is reset to 0 during rendering
+    run_to_end_of_full_frame(&mut emu_state);
+    emu_state.program_counter = 0x802F;
+    emu_state.run_one_instruction().unwrap();
+    assert_eq!(emu_state.reg_a, 0x0F);
+}
+
+// Render a frame with a solid background and sprite 0 in the leftmost 8 pixels, returning whether sprite 0 hit was set
+fn run_ppu_mask_test(mask: u8) -> (bool, emulator::EmuState) {
+    let program = [
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00; STA $2006
+        0xA9, 0x0F, 0x8D, 0x07, 0x20, // LDA #$0F; STA $2007    backdrop
+        0xA9, 0x16, 0x8D, 0x07, 0x20, // LDA #$16; STA $2007    background colour 1
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F; STA $2006
+        0xA9, 0x11, 0x8D, 0x06, 0x20, // LDA #$11; STA $2006
+        0xA9, 0x2A, 0x8D, 0x07, 0x20, // LDA #$2A; STA $2007    sprite colour 1
+        0xA9, 0x90, 0x8D, 0x14, 0x40, // LDA #$90; STA $4014    OAM DMA from ROM
+        0xA9, 0x00, 0x8D, 0x05, 0x20, // LDA #$00; STA $2005
+        0x8D, 0x05, 0x20,             // STA $2005
+        0xA9, mask, 0x8D, 0x01, 0x20, // LDA #mask; STA $2001
+        0x2C, 0x02, 0x20,             // BIT $2002
+        0x50, 0xFB,                   // BVC $8035              wait for sprite 0 hit
+        0xA9, 0x42,                   // LDA #$42
+        0x4C, 0x3C, 0x80,             // JMP *
+    ];
+    let mut image = make_nrom_image(&program);
+    image[16 + 0x1000 .. 16 + 0x1100].fill(0xF0);
+    image[16 + 0x1000 .. 16 + 0x1004].copy_from_slice(&[16, 0, 0, 0]); // sprite 0 at (0, 17)
+    image[16 + 0x4000 .. 16 + 0x4008].fill(0xFF); // tile 0 is solid, and fills the nametable
+
+    let rom_state = emulator::RomState::from_bytes(&image).unwrap();
+    let mut emu_state = emulator::EmuState::from_rom(rom_state).unwrap();
+    run_to_end_of_full_frame(&mut emu_state);
+    return (emu_state.reg_a == 0x42, emu_state);
+}
+
+#[test]
+fn ppu_mask() {
+    let pixel = |emu_state: &emulator::EmuState, x: usize, y: usize| {
+        let index = (y * 256 + x) * 4;
+        return emu_state.frame_buffer()[index .. index + 4].to_vec();
+    };
+
+    let (hit, plain) = run_ppu_mask_test(0x1E);
+    assert!(hit);
+    let background = pixel(&plain, 20, 20);
+    let sprite = pixel(&plain, 0, 20);
+    assert_ne!(background, sprite);
+    assert_eq!(pixel(&plain, 0, 0), background);
+
+    // Clipping both hides the leftmost pixels and prevents sprite 0 hit
+    let (hit, emu_state) = run_ppu_mask_test(0x18);
+    assert!(!hit);
+    assert_ne!(pixel(&emu_state, 0, 20), background);
+    assert_ne!(pixel(&emu_state, 0, 20), sprite);
+    assert_eq!(pixel(&emu_state, 8, 20), background);
+
+    // Clipping only sprites
+    let (hit, emu_state) = run_ppu_mask_test(0x1A);
+    assert!(!hit);
+    assert_eq!(pixel(&emu_state, 0, 20), background);
+
+    // Clipping only the background
+    let (hit, emu_state) = run_ppu_mask_test(0x1C);
+    assert!(!hit);
+    assert_eq!(pixel(&emu_state, 0, 20), sprite);
+
+    // Greyscale
+    let (_, emu_state) = run_ppu_mask_test(0x1F);
+    for x in [0, 20] {
+        let grey = pixel(&emu_state, x, 20);
+        assert!(grey[0].abs_diff(grey[1]) <= 4 && grey[1].abs_diff(grey[2]) <= 4, "{:?}", grey);
+    }
+
+    // Red emphasis darkens green and blue
+    let (_, emu_state) = run_ppu_mask_test(0x3E);
+    let emphasised = pixel(&emu_state, 20, 20);
+    assert_eq!(emphasised[0], background[0]);
+    assert!(emphasised[1] < background[1] || background[1] == 0);
+    assert!(emphasised[2] < background[2]);
+}
+
+#[test]
+fn sprite_overflow_timing() {
+    // Eight sprites on line 17, then twelve that aren't, then one more that is
+    let mut oam = [0xF0u8; 256];
+    for i in (0..8).chain(20..21) {
+        oam[i * 4] = 16;
+    }
+
+    // Evaluation starts at dot 65 on line 16. Each sprite in range takes 8 dots, and each one out of range takes 2,
+    // so sprite 20 is read on dot 65 + 8 * 8 + 12 * 2 = 153.
+    let mut emu_state = make_sprite_overflow_test(&oam, true);
+    while emu_state.reg_a != 0x42 { emu_state.run_one_instruction().unwrap(); }
+    assert_eq!(emu_state.ppu_y, 16);
+    assert!((153 .. 153 + 30).contains(&emu_state.ppu_x), "{}", emu_state.ppu_x);
+}